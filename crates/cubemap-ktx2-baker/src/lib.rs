@@ -113,11 +113,11 @@ fn radical_inverse_vd_c(bits: u32) -> f32 {
     return bits as f32 * 2.3283064365386963e-10; // / 0x100000000
 }
 
-fn hammersley(i: u32, n: u32) -> Vec2 {
+pub fn hammersley(i: u32, n: u32) -> Vec2 {
     Vec2::new((i as f32) / (n as f32), radical_inverse_vd_c(i))
 }
 
-fn importance_sample_ggx(x_i: Vec2, n: Vec3, roughness: f32) -> Vec3 {
+pub fn importance_sample_ggx(x_i: Vec2, n: Vec3, roughness: f32) -> Vec3 {
     let a = roughness * roughness;
 
     let phi = 2.0 * PI * x_i.x;
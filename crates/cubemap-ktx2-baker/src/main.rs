@@ -2,6 +2,7 @@ use clap::Parser;
 use cubemap_ktx2_baker::equirectangular_to_prefiltered_cubemap;
 use image::Rgb32FImage;
 use postcard::to_io;
+use sh_coefficient_baker::{Windowing, report_min_reconstructed_value};
 use std::{
     fs::{File, create_dir_all, remove_dir_all},
     path::PathBuf,
@@ -33,6 +34,10 @@ struct Args {
     #[arg(short, long)]
     bake_irradiance_sh: bool,
 
+    /// Windowing function applied to the SH coefficients to reduce ringing around bright light sources.
+    #[arg(short, long, value_enum, default_value_t = Windowing::None)]
+    windowing: Windowing,
+
     output_path: PathBuf,
 }
 
@@ -128,9 +133,11 @@ fn main() {
         .map(|x| x.into_rgb32f())
         .collect();
 
-    let sh_coefs = sh_coefficient_baker::process(args.num_bands, true, &cube_faces)
+    let sh_coefs = sh_coefficient_baker::process(args.num_bands, true, args.windowing, &cube_faces)
         .expect("Failed to extract SH coefficients from cube map faces.");
 
+    report_min_reconstructed_value(args.num_bands, &sh_coefs);
+
     let sh_file_path = args.output_path.with_file_name(format!(
         "{}.bin",
         args.output_path
@@ -0,0 +1,77 @@
+// Sources:
+// - https://google.github.io/filament/Filament.html#lighting/imagebasedlights/processinglightprobes
+// - https://google.github.io/filament/Filament.html#materialsystem/improvingthebrdfs/energylossinspecularreflectance
+use anyhow::Result;
+use cubemap_ktx2_baker::{hammersley, importance_sample_ggx};
+use glam::Vec3;
+use image::{Rgb, Rgb32FImage};
+use rayon::prelude::*;
+
+/// Computes the DFG term of the split sum approximation for image based lighting.
+/// The x axis of the LUT is NoV and the y axis is the perceptual roughness, both
+/// sampled at texel centers.
+///
+/// The channels are laid out so that both the specular color and the energy
+/// compensation for multiple scattering can be computed from them:
+/// - R: ∫ Gv * Fc
+/// - G: ∫ Gv
+///
+/// where Fc = (1 - VoH)^5. The blue channel is left at 0.
+pub fn integrate_dfg_lut(size: u32, sample_count: u32) -> Result<Rgb32FImage> {
+    if size == 0 || sample_count == 0 {
+        anyhow::bail!("LUT size and sample count need to be greater than 0.");
+    }
+
+    let mut lut = Rgb32FImage::new(size, size);
+
+    lut.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+        let n_dot_v = (x as f32 + 0.5) / size as f32;
+        let perceptual_roughness = (y as f32 + 0.5) / size as f32;
+
+        let (gv_fc, gv) = integrate_dfg(n_dot_v, perceptual_roughness, sample_count);
+        *pixel = Rgb([gv_fc, gv, 0.0]);
+    });
+
+    Ok(lut)
+}
+
+fn integrate_dfg(n_dot_v: f32, perceptual_roughness: f32, sample_count: u32) -> (f32, f32) {
+    let roughness = perceptual_roughness * perceptual_roughness;
+
+    // The integral is isotropic, so any view vector with the right NoV will do
+    let n = Vec3::Z;
+    let v = Vec3::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+
+    let mut gv_fc_sum = 0.0;
+    let mut gv_sum = 0.0;
+    for i in 0..sample_count {
+        let x_i = hammersley(i, sample_count);
+        // importance_sample_ggx squares the roughness itself
+        let h = importance_sample_ggx(x_i, n, perceptual_roughness);
+        let l = 2.0 * v.dot(h) * h - v;
+
+        let n_dot_l = l.z;
+        let n_dot_h = h.z;
+        let v_dot_h = v.dot(h);
+        if n_dot_l > 0.0 && n_dot_h > 0.0 {
+            // The pdf of l is D * NoH / (4 * VoH), so the D term cancels out
+            let gv =
+                v_smith_ggx_correlated(n_dot_v, n_dot_l, roughness) * n_dot_l * v_dot_h / n_dot_h;
+            let fc = (1.0 - v_dot_h).clamp(0.0, 1.0).powi(5);
+
+            gv_fc_sum += gv * fc;
+            gv_sum += gv;
+        }
+    }
+
+    let scale = 4.0 / sample_count as f32;
+    (gv_fc_sum * scale, gv_sum * scale)
+}
+
+/// Exact version of the approximation used by V_SmithGGXCorrelated in filament-brdf.slang
+fn v_smith_ggx_correlated(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let a2 = roughness * roughness;
+    let ggx_v = n_dot_l * (n_dot_v * n_dot_v * (1.0 - a2) + a2).sqrt();
+    let ggx_l = n_dot_v * (n_dot_l * n_dot_l * (1.0 - a2) + a2).sqrt();
+    0.5 / (ggx_v + ggx_l)
+}
@@ -0,0 +1,92 @@
+use clap::Parser;
+use dfg_lut_baker::integrate_dfg_lut;
+use image::DynamicImage;
+use std::{
+    fs::{create_dir_all, remove_dir_all},
+    path::PathBuf,
+    process::Command,
+};
+
+/// CLI tool to bake the DFG term of the split sum approximation into a 2D ktx2 LUT,
+/// to be used for image based lighting with multiple scattering energy compensation.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Edge resolution of the output ktx2 texture.
+    #[arg(short, long, default_value_t = 128)]
+    resolution: u32,
+
+    /// Number of samples to take when computing each pixel of the LUT.
+    #[arg(short, long, default_value_t = 1024)]
+    sample_count: u32,
+
+    output_path: PathBuf,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if args.output_path.is_dir() {
+        panic!("Given output path is a directory: {:#?}", args.output_path);
+    }
+
+    let temp_dir = std::env::current_dir().unwrap().join("temp/");
+    create_dir_all(&temp_dir).unwrap();
+    let tiled_path = temp_dir.join("dfg-tiled.exr");
+    let scanline_path = temp_dir.join("dfg-scanline.exr");
+
+    println!(
+        "Integrating {}x{} DFG LUT with {} samples per pixel.",
+        args.resolution, args.resolution, args.sample_count
+    );
+    let lut = integrate_dfg_lut(args.resolution, args.sample_count)
+        .expect("Failed to integrate DFG LUT.");
+
+    println!("Saving tiled EXR result.");
+    DynamicImage::ImageRgb32F(lut)
+        .save(&tiled_path)
+        .expect("Failed to save DFG LUT");
+
+    println!("Converting tiled EXR file to scanline.");
+    let oiio_output = Command::new("oiiotool")
+        .args([
+            tiled_path.to_str().unwrap(),
+            "--scanline",
+            "-o",
+            scanline_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    if !oiio_output.status.success() {
+        eprintln!(
+            "ERROR: Failed to convert tiled EXR to scanline EXR: \n{}",
+            String::from_utf8(oiio_output.stderr).unwrap()
+        );
+    }
+
+    println!("Compiling EXR file into a ktx2 texture.");
+    let ktx_output = Command::new("ktx")
+        .args([
+            "create",
+            "--format",
+            "R16G16_SFLOAT",
+            "--zstd",
+            "20",
+            "--assign-primaries",
+            "none",
+            "--assign-tf",
+            "linear",
+            scanline_path.to_str().unwrap(),
+            args.output_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    if !ktx_output.status.success() {
+        eprintln!(
+            "ERROR: Failed to bake DFG LUT as ktx2: \n{}",
+            String::from_utf8(ktx_output.stderr).unwrap()
+        );
+    }
+
+    remove_dir_all(temp_dir).unwrap();
+}
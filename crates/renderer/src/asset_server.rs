@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex, Weak},
+};
+
+use crate::{
+    mipmaps::{MipmapGenerator, MipmapMode},
+    model::Model,
+    resources::{self, ResourceError},
+    sampler_cache::{SamplerCache, SamplerKey},
+    texture::{FallbackTextures, Texture, TextureImportOptions},
+};
+
+/// Everything about an import that changes the resulting [`Texture`]. Imports
+/// that only differ in their label or LOD bias share the same texture.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TextureKey {
+    path: String,
+    srgb: Option<bool>,
+    mipmaps: Option<MipmapMode>,
+    sampler: SamplerKey,
+}
+
+/// Assets of one type, keyed by what they were loaded from. Only weak handles
+/// are stored, so an asset gets dropped as soon as the last handle to it is
+/// gone, and its entry is removed on the next insert.
+struct AssetMap<K, T> {
+    assets: HashMap<K, Weak<T>>,
+    load_count: usize,
+    request_count: usize,
+}
+
+impl<K, T> Default for AssetMap<K, T> {
+    fn default() -> Self {
+        Self {
+            assets: HashMap::new(),
+            load_count: 0,
+            request_count: 0,
+        }
+    }
+}
+
+impl<K: Eq + Hash, T> AssetMap<K, T> {
+    fn get(&mut self, key: &K) -> Option<Arc<T>> {
+        self.request_count += 1;
+        self.assets.get(key).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, key: K, asset: &Arc<T>) {
+        self.load_count += 1;
+        self.evict_unreferenced();
+        self.assets.insert(key, Arc::downgrade(asset));
+    }
+
+    /// Removes the entries of assets that have been dropped.
+    fn evict_unreferenced(&mut self) -> usize {
+        let count = self.assets.len();
+        self.assets.retain(|_, asset| asset.strong_count() > 0);
+        count - self.assets.len()
+    }
+
+    #[cfg(debug_assertions)]
+    fn live_count(&self) -> usize {
+        self.assets
+            .values()
+            .filter(|asset| asset.strong_count() > 0)
+            .count()
+    }
+}
+
+/// Loads textures and models through [`resources`], and hands out shared
+/// handles for files that are already loaded, so that e.g. two materials using
+/// the same texture only upload it once.
+#[derive(Default)]
+pub struct AssetServer {
+    sampler_cache: SamplerCache,
+    mipmap_generator: MipmapGenerator,
+    textures: Mutex<AssetMap<TextureKey, Texture>>,
+    models: Mutex<AssetMap<String, Model>>,
+}
+
+impl AssetServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sampler_cache(&self) -> &SamplerCache {
+        &self.sampler_cache
+    }
+
+    pub async fn load_texture(
+        &self,
+        file_name: &str,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        options: TextureImportOptions<'_>,
+    ) -> Result<Arc<Texture>, ResourceError> {
+        let key = TextureKey {
+            path: file_name.to_string(),
+            srgb: options.srgb,
+            mipmaps: options.generate_mipmaps,
+            sampler: SamplerKey::from(&options.sampler_descriptor()),
+        };
+        // The lock is not held while loading, since that awaits
+        if let Some(texture) = self.textures.lock().unwrap().get(&key) {
+            return Ok(texture);
+        }
+
+        let texture = Arc::new(
+            resources::load_texture(
+                file_name,
+                device,
+                queue,
+                &self.sampler_cache,
+                Some(&self.mipmap_generator),
+                options,
+            )
+            .await?,
+        );
+        self.textures.lock().unwrap().insert(key, &texture);
+
+        Ok(texture)
+    }
+
+    pub async fn load_model(
+        &self,
+        file_name: &str,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        fallback_textures: &FallbackTextures,
+    ) -> Result<Arc<Model>, ResourceError> {
+        let key = file_name.to_string();
+        if let Some(model) = self.models.lock().unwrap().get(&key) {
+            return Ok(model);
+        }
+
+        let model = Arc::new(
+            resources::load_model(file_name, queue, device, self, fallback_textures).await?,
+        );
+        self.models.lock().unwrap().insert(key, &model);
+
+        Ok(model)
+    }
+
+    /// Removes the entries of all assets that are no longer used anywhere and
+    /// returns how many were removed.
+    #[allow(unused)]
+    pub fn evict_unreferenced(&self) -> usize {
+        self.textures.lock().unwrap().evict_unreferenced()
+            + self.models.lock().unwrap().evict_unreferenced()
+    }
+
+    /// Logs how many assets were loaded and how many loads were deduplicated.
+    /// Only does anything in debug builds.
+    pub fn log_stats(&self) {
+        #[cfg(debug_assertions)]
+        {
+            let textures = self.textures.lock().unwrap();
+            let models = self.models.lock().unwrap();
+            log::debug!(
+                "Asset server: loaded {} textures for {} requests ({} alive), {} models for {} requests ({} alive)",
+                textures.load_count,
+                textures.request_count,
+                textures.live_count(),
+                models.load_count,
+                models.request_count,
+                models.live_count(),
+            );
+        }
+        self.sampler_cache.log_stats();
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::Context;
+use cgmath::Deg;
+use clap::{Parser, Subcommand, ValueEnum};
+use renderer::{CameraAnimation, CameraPath, RenderPath, ScreenshotFormats, Sequence, Turntable};
+use std::path::{Path, PathBuf};
+
+/// CLI tool to render turntables or camera paths of the models in res/ to
+/// numbered PNG and EXR frames, without opening a window.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Model in res/ to render. Every OBJ model in res/ is rendered if not given.
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// Width of the frames in pixels.
+    #[arg(long, default_value_t = 1920)]
+    width: u32,
+
+    /// Height of the frames in pixels.
+    #[arg(long, default_value_t = 1080)]
+    height: u32,
+
+    /// Frames per second of the sequence, independent of how fast they render.
+    #[arg(long, default_value_t = 30.0)]
+    fps: f32,
+
+    /// Files to write for every frame.
+    #[arg(long, value_enum, default_value_t = Format::Both)]
+    format: Format,
+
+    /// Directory the frames are written to, with a subdirectory per model.
+    #[arg(short, long, default_value = "sequences")]
+    output: PathBuf,
+
+    /// How the meshes are lit, to compare the two paths.
+    #[arg(long, value_enum, default_value_t = RenderPathArg::Forward)]
+    render_path: RenderPathArg,
+
+    #[command(subcommand)]
+    camera: CameraArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum CameraArgs {
+    /// Orbit the camera around the origin.
+    Turntable {
+        /// Number of frames of the whole turntable.
+        #[arg(long, default_value_t = 120)]
+        frames: u32,
+
+        /// Distance of the camera to the origin.
+        #[arg(long, default_value_t = 4.0)]
+        radius: f32,
+
+        /// Pitch of the camera in degrees.
+        #[arg(long, default_value_t = -32.0, allow_negative_numbers = true)]
+        pitch: f32,
+
+        /// Yaw of the first frame in degrees.
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        yaw: f32,
+
+        /// Full turns over the whole turntable.
+        #[arg(long, default_value_t = 1.0)]
+        revolutions: f32,
+    },
+    /// Follow a keyframed camera path.
+    Path {
+        /// Text file with one `time x y z yaw pitch` keyframe per line, with
+        /// the time in seconds and the angles in degrees.
+        file: PathBuf,
+
+        /// Number of frames. Defaults to the duration of the path.
+        #[arg(long)]
+        frames: Option<u32>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RenderPathArg {
+    Forward,
+    Deferred,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Png,
+    Exr,
+    Both,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let args = Args::parse();
+
+    let (camera, frame_count) = match args.camera {
+        CameraArgs::Turntable {
+            frames,
+            radius,
+            pitch,
+            yaw,
+            revolutions,
+        } => {
+            let turntable = Turntable {
+                radius,
+                pitch: Deg(pitch),
+                start_yaw: Deg(yaw),
+                revolutions,
+                ..Default::default()
+            };
+            (CameraAnimation::Turntable(turntable), frames)
+        }
+        CameraArgs::Path { file, frames } => {
+            let path = CameraPath::load(&file)?;
+            // Include a frame for the last keyframe
+            let frames = frames.unwrap_or((path.duration() * args.fps).floor() as u32 + 1);
+            (CameraAnimation::Path(path), frames)
+        }
+    };
+
+    let sequence = Sequence {
+        width: args.width,
+        height: args.height,
+        frame_count,
+        frames_per_second: args.fps,
+        camera,
+        formats: ScreenshotFormats {
+            png: matches!(args.format, Format::Png | Format::Both),
+            exr: matches!(args.format, Format::Exr | Format::Both),
+        },
+        render_path: match args.render_path {
+            RenderPathArg::Forward => RenderPath::Forward,
+            RenderPathArg::Deferred => RenderPath::Deferred,
+        },
+    };
+
+    let models = match args.model {
+        Some(model) => vec![model],
+        None => renderer::list_models()?,
+    };
+
+    for model in &models {
+        let model_name = Path::new(model)
+            .file_stem()
+            .context("Model file name is empty.")?;
+        let output_dir = args.output.join(model_name);
+
+        println!(
+            "Rendering {frame_count} frames of {model} to {}.",
+            output_dir.display()
+        );
+        pollster::block_on(sequence.render(model, &output_dir))
+            .with_context(|| format!("Failed to render {model}"))?;
+    }
+
+    Ok(())
+}
@@ -4,16 +4,88 @@ use wgpu::{BindGroup, BindGroupLayout, Buffer, util::DeviceExt};
 
 use crate::{
     input_handling::{ButtonState, InputData},
+    texture::Texture,
     wgpu_traits::AsBindGroup,
 };
 
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
 
-pub struct Projection {
-    pub aspect: f32,
-    pub fov_y: Rad<f32>,
-    pub z_near: f32,
-    pub z_far: f32,
+/// cgmath is built for OpenGL, where depth goes from -1 to 1 in clip space.
+/// WGPU uses 0 to 1, like DirectX, Metal and Vulkan.
+#[rustfmt::skip]
+const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+/// Flips depth in clip space, from `z` to `w - z`.
+#[rustfmt::skip]
+const REVERSE_Z_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, -1.0, 0.0,
+    0.0, 0.0, 1.0, 1.0,
+);
+
+/// How depth is stored in the depth buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthMode {
+    /// Depth goes from 0 at the near plane to 1 at the far plane.
+    #[allow(unused)]
+    Standard,
+    /// Depth goes from 1 at the near plane to 0 at a far plane that is
+    /// infinitely far away, so nothing gets clipped for being too far. Floats
+    /// are most precise close to 0, which cancels out that perspective depth
+    /// bunches up close to the far plane, so there is less z-fighting far away.
+    ///
+    /// Orthographic projections keep their far plane, but still flip depth.
+    ReverseInfinite,
+}
+
+impl DepthMode {
+    /// Depth of the far plane, which is also what the depth buffer is cleared to.
+    pub fn far_depth(self) -> f32 {
+        match self {
+            DepthMode::Standard => 1.0,
+            DepthMode::ReverseInfinite => 0.0,
+        }
+    }
+
+    /// Depth test for pipelines that render into the depth buffer.
+    pub fn depth_stencil_state(self) -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: match self {
+                DepthMode::Standard => wgpu::CompareFunction::LessEqual,
+                DepthMode::ReverseInfinite => wgpu::CompareFunction::GreaterEqual,
+            },
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Projection {
+    Perspective {
+        aspect: f32,
+        fov_y: Rad<f32>,
+        z_near: f32,
+        z_far: f32,
+    },
+    /// Parallel projection that shows as much as a perspective projection
+    /// with `fov_y` would at `focus_distance`. Switching between the two keeps
+    /// whatever is at the focus distance the same size on screen.
+    Orthographic {
+        aspect: f32,
+        fov_y: Rad<f32>,
+        focus_distance: f32,
+        z_near: f32,
+        z_far: f32,
+    },
 }
 
 impl Projection {
@@ -24,7 +96,7 @@ impl Projection {
         z_near: f32,
         z_far: f32,
     ) -> Self {
-        Self {
+        Self::Perspective {
             aspect: width as f32 / height as f32,
             fov_y: fov_y.into(),
             z_near,
@@ -33,11 +105,169 @@ impl Projection {
     }
 
     pub fn resize(&mut self, width: u32, height: u32) {
-        self.aspect = width as f32 / height as f32;
+        match self {
+            Self::Perspective { aspect, .. } | Self::Orthographic { aspect, .. } => {
+                *aspect = width as f32 / height as f32;
+            }
+        }
     }
 
-    pub fn calc_matrix(&self) -> Matrix4<f32> {
-        perspective(self.fov_y, self.aspect, self.z_near, self.z_far)
+    pub fn fov_y(&self) -> Rad<f32> {
+        match self {
+            Self::Perspective { fov_y, .. } | Self::Orthographic { fov_y, .. } => *fov_y,
+        }
+    }
+
+    pub fn is_orthographic(&self) -> bool {
+        matches!(self, Self::Orthographic { .. })
+    }
+
+    /// Switches between perspective and orthographic, keeping the field of
+    /// view and clip planes.
+    pub fn set_orthographic(&mut self, orthographic: bool) {
+        *self = match *self {
+            Self::Perspective {
+                aspect,
+                fov_y,
+                z_near,
+                z_far,
+            } if orthographic => Self::Orthographic {
+                aspect,
+                fov_y,
+                focus_distance: 1.0,
+                z_near,
+                z_far,
+            },
+            Self::Orthographic {
+                aspect,
+                fov_y,
+                z_near,
+                z_far,
+                ..
+            } if !orthographic => Self::Perspective {
+                aspect,
+                fov_y,
+                z_near,
+                z_far,
+            },
+            projection => projection,
+        };
+    }
+
+    /// Sets the distance an orthographic projection matches the perspective
+    /// one at. Does nothing for perspective projections.
+    pub fn set_focus_distance(&mut self, distance: f32) {
+        if let Self::Orthographic { focus_distance, .. } = self {
+            *focus_distance = distance;
+        }
+    }
+
+    pub fn calc_matrix(&self, depth_mode: DepthMode) -> Matrix4<f32> {
+        match (*self, depth_mode) {
+            (
+                Self::Perspective {
+                    aspect,
+                    fov_y,
+                    z_near,
+                    ..
+                },
+                DepthMode::ReverseInfinite,
+            ) => {
+                // The limit of the reversed perspective matrix as the far plane
+                // goes to infinity. Depth ends up as z_near / distance.
+                let focal_length = 1.0 / (fov_y / 2.0).tan();
+                #[rustfmt::skip]
+                let matrix = Matrix4::new(
+                    focal_length / aspect, 0.0, 0.0, 0.0,
+                    0.0, focal_length, 0.0, 0.0,
+                    0.0, 0.0, 0.0, -1.0,
+                    0.0, 0.0, z_near, 0.0,
+                );
+                matrix
+            }
+            (
+                Self::Perspective {
+                    aspect,
+                    fov_y,
+                    z_near,
+                    z_far,
+                },
+                DepthMode::Standard,
+            ) => OPENGL_TO_WGPU_MATRIX * perspective(fov_y, aspect, z_near, z_far),
+            (
+                Self::Orthographic {
+                    aspect,
+                    fov_y,
+                    focus_distance,
+                    z_near,
+                    z_far,
+                },
+                depth_mode,
+            ) => {
+                let half_height = focus_distance * (fov_y / 2.0).tan();
+                let half_width = half_height * aspect;
+                let matrix = OPENGL_TO_WGPU_MATRIX
+                    * ortho(
+                        -half_width,
+                        half_width,
+                        -half_height,
+                        half_height,
+                        z_near,
+                        z_far,
+                    );
+
+                match depth_mode {
+                    DepthMode::Standard => matrix,
+                    DepthMode::ReverseInfinite => REVERSE_Z_MATRIX * matrix,
+                }
+            }
+        }
+    }
+}
+
+/// Exposure settings of a physical camera, which turn the luminance of the
+/// scene in cd/m² into the values that get tonemapped.
+/// Source: "Physically Based Rendering in Filament", section 8.1
+#[derive(Clone, Copy, Debug)]
+pub struct PhysicalCamera {
+    /// Relative aperture in f-stops
+    pub aperture: f32,
+    /// Shutter speed in seconds
+    pub shutter_speed: f32,
+    /// Sensor sensitivity in ISO
+    pub sensitivity: f32,
+}
+
+impl PhysicalCamera {
+    /// f/2.8, 1/60 s, ISO 800, for indoor scenes lit by light bulbs.
+    pub const INDOOR: Self = Self {
+        aperture: 2.8,
+        shutter_speed: 1.0 / 60.0,
+        sensitivity: 800.0,
+    };
+
+    /// f/16, 1/125 s, ISO 100, the sunny 16 rule for scenes lit by the sun.
+    pub const SUNNY_16: Self = Self {
+        aperture: 16.0,
+        shutter_speed: 1.0 / 125.0,
+        sensitivity: 100.0,
+    };
+
+    /// Exposure value at ISO 100 that is equivalent to these settings.
+    pub fn ev100(&self) -> f32 {
+        (self.aperture * self.aperture / self.shutter_speed * 100.0 / self.sensitivity).log2()
+    }
+
+    /// Factor from luminance to the normalized sensor values, based on the
+    /// luminance that saturates the sensor at this EV100.
+    pub fn exposure(&self) -> f32 {
+        1.0 / (1.2 * 2.0_f32.powf(self.ev100()))
+    }
+}
+
+impl Default for PhysicalCamera {
+    fn default() -> Self {
+        Self::INDOOR
     }
 }
 
@@ -46,24 +276,36 @@ pub struct CameraProperties {
     pub yaw: Rad<f32>,
     pub pitch: Rad<f32>,
     pub projection: Projection,
+    pub depth_mode: DepthMode,
+    pub physical_camera: PhysicalCamera,
+    /// Subpixel offset of the projection in NDC, which temporal anti-aliasing
+    /// changes every frame.
+    pub jitter: Vector2<f32>,
 }
 
 impl CameraProperties {
-    /// Calculate the View Matrix (this is without the projection)
-    pub fn calc_view_matrix(&self) -> Matrix4<f32> {
+    /// Direction the camera is looking in.
+    pub fn forward(&self) -> Vector3<f32> {
         let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
 
-        Matrix4::look_to_rh(
-            self.position,
-            Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize(),
-            Vector3::unit_y(),
-        )
+        Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize()
+    }
+
+    /// Calculate the View Matrix (this is without the projection)
+    pub fn calc_view_matrix(&self) -> Matrix4<f32> {
+        Matrix4::look_to_rh(self.position, self.forward(), Vector3::unit_y())
     }
 
-    /// Calculate the Projection Matrix for perspective.
+    /// Calculate the Projection Matrix, without the jitter.
     pub fn calc_proj_matrix(&self) -> Matrix4<f32> {
-        self.projection.calc_matrix()
+        self.projection.calc_matrix(self.depth_mode)
+    }
+
+    /// Moves the projected image by the jitter. The offset is scaled by w, so it
+    /// is the same after the perspective divide.
+    pub fn calc_jitter_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.jitter.extend(0.0))
     }
 }
 
@@ -104,26 +346,49 @@ impl Camera {
 pub struct CameraUniform {
     view_pos: [f32; 4],
     view: [[f32; 4]; 4],
+    /// Includes the jitter, for rasterizing
     view_proj: [[f32; 4]; 4],
+    /// Without the jitter, for motion vectors
+    unjittered_view_proj: [[f32; 4]; 4],
+    /// Unjittered view projection of the previous frame
+    prev_view_proj: [[f32; 4]; 4],
     inv_proj: [[f32; 4]; 4],
     inv_view: [[f32; 4]; 4],
+    jitter: [f32; 2],
+    /// Depth of the far plane, which depends on the depth mode
+    far_depth: f32,
+    /// Factor from luminance to the values that get tonemapped. Shaders apply
+    /// it before writing the HDR render, so that it can't overflow.
+    exposure: f32,
 }
 
 impl CameraUniform {
     pub fn update_view_proj(&mut self, camera: &CameraProperties) {
-        self.view_pos = camera.position.to_homogeneous().into();
+        // For orthographic projections the camera is infinitely far away, so
+        // this is the direction towards it instead, with w = 0. Shaders get the
+        // view direction of either with `view_pos.xyz - world_pos * view_pos.w`
+        self.view_pos = if camera.projection.is_orthographic() {
+            (-camera.forward()).extend(0.0).into()
+        } else {
+            camera.position.to_homogeneous().into()
+        };
         let proj = camera.calc_proj_matrix();
         let view = camera.calc_view_matrix();
         let view_proj = proj * view;
 
         self.view = view.into();
-        self.view_proj = view_proj.into();
+        self.view_proj = (camera.calc_jitter_matrix() * view_proj).into();
+        self.prev_view_proj = self.unjittered_view_proj;
+        self.unjittered_view_proj = view_proj.into();
+        self.jitter = camera.jitter.into();
 
         // View Matrix is always Orthonormal (each column is perpendicular
         // to each other). So the transpose is guaranteed to be the inverse
         self.inv_view = view.transpose().into();
         // Things aren't so easy for the Projection Matrix though :D
         self.inv_proj = proj.invert().unwrap().into();
+        self.far_depth = camera.depth_mode.far_depth();
+        self.exposure = camera.physical_camera.exposure();
     }
 }
 
@@ -134,12 +399,80 @@ impl Default for CameraUniform {
             view_pos: [0.0; 4],
             view: cgmath::Matrix4::identity().into(),
             view_proj: cgmath::Matrix4::identity().into(),
+            unjittered_view_proj: cgmath::Matrix4::identity().into(),
+            prev_view_proj: cgmath::Matrix4::identity().into(),
             inv_proj: cgmath::Matrix4::identity().into(),
             inv_view: cgmath::Matrix4::identity().into(),
+            jitter: [0.0; 2],
+            far_depth: 1.0,
+            exposure: 1.0,
+        }
+    }
+}
+
+/// Frames it takes the orbit camera to move to a preset.
+const TRANSITION_FRAMES: u32 = 30;
+
+/// A view of the orbit camera that it can move to.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraPreset {
+    pub target: Point3<f32>,
+    pub orbit_radius: f32,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+    pub orthographic: bool,
+}
+
+impl CameraPreset {
+    /// Looking at the target along -Z.
+    pub fn front(target: Point3<f32>, orbit_radius: f32) -> Self {
+        Self::axis_aligned(target, orbit_radius, Deg(-90.0), Deg(0.0))
+    }
+
+    /// Looking at the target along -X.
+    pub fn side(target: Point3<f32>, orbit_radius: f32) -> Self {
+        Self::axis_aligned(target, orbit_radius, Deg(180.0), Deg(0.0))
+    }
+
+    /// Looking down at the target, with -Z pointing up on screen.
+    pub fn top(target: Point3<f32>, orbit_radius: f32) -> Self {
+        Self::axis_aligned(target, orbit_radius, Deg(-90.0), -Rad(SAFE_FRAC_PI_2))
+    }
+
+    /// Canonical views are orthographic, so that they line up with the axes.
+    fn axis_aligned<Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(
+        target: Point3<f32>,
+        orbit_radius: f32,
+        yaw: Y,
+        pitch: P,
+    ) -> Self {
+        Self {
+            target,
+            orbit_radius,
+            yaw: yaw.into(),
+            pitch: pitch.into(),
+            orthographic: true,
+        }
+    }
+
+    /// Interpolates the view, with the yaw taking the shorter way around.
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            target: self.target + (other.target - self.target) * t,
+            orbit_radius: self.orbit_radius + (other.orbit_radius - self.orbit_radius) * t,
+            yaw: self.yaw + (other.yaw - self.yaw).normalize_signed() * t,
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+            orthographic: other.orthographic,
         }
     }
 }
 
+struct CameraTransition {
+    from: CameraPreset,
+    to: CameraPreset,
+    frame: u32,
+}
+
 pub struct OrbitCameraController {
     target: cgmath::Point3<f32>,
     orbit_sensitivity: f32,
@@ -147,6 +480,8 @@ pub struct OrbitCameraController {
     orbit_radius: f32,
     yaw: Rad<f32>,
     pitch: Rad<f32>,
+    orthographic: bool,
+    transition: Option<CameraTransition>,
 }
 
 impl OrbitCameraController {
@@ -165,10 +500,83 @@ impl OrbitCameraController {
             orbit_radius,
             yaw: yaw.into(),
             pitch: pitch.into(),
+            orthographic: false,
+            transition: None,
+        }
+    }
+
+    pub fn target(&self) -> Point3<f32> {
+        self.target
+    }
+
+    pub fn orbit_radius(&self) -> f32 {
+        self.orbit_radius
+    }
+
+    /// The current view, to come back to later.
+    pub fn preset(&self) -> CameraPreset {
+        CameraPreset {
+            target: self.target,
+            orbit_radius: self.orbit_radius,
+            yaw: self.yaw,
+            pitch: self.pitch,
+            orthographic: self.orthographic,
+        }
+    }
+
+    /// Smoothly moves the camera to `preset` over the next frames. Switching
+    /// to perspective happens right away, and to orthographic once the camera
+    /// arrived, so the camera is never orthographic while it turns.
+    pub fn animate_to(&mut self, preset: CameraPreset) {
+        if !preset.orthographic {
+            self.orthographic = false;
+        }
+        self.transition = Some(CameraTransition {
+            from: self.preset(),
+            to: preset,
+            frame: 0,
+        });
+    }
+
+    pub fn set_orthographic(&mut self, orthographic: bool) {
+        self.orthographic = orthographic;
+    }
+
+    pub fn is_orthographic(&self) -> bool {
+        self.orthographic
+    }
+
+    /// Moves the camera one frame further towards the preset it animates to.
+    pub fn advance_transition(&mut self) {
+        let Some(transition) = &mut self.transition else {
+            return;
+        };
+
+        transition.frame += 1;
+        let t = transition.frame as f32 / TRANSITION_FRAMES as f32;
+        // Ease in and out
+        let view = transition
+            .from
+            .lerp(&transition.to, t * t * (3.0 - 2.0 * t));
+
+        self.target = view.target;
+        self.orbit_radius = view.orbit_radius;
+        self.yaw = view.yaw.normalize();
+        self.pitch = view.pitch;
+
+        if transition.frame >= TRANSITION_FRAMES {
+            self.orthographic = view.orthographic;
+            self.transition = None;
         }
     }
 
     pub fn process_input(&mut self, input: &InputData) {
+        // Any input takes over from a running transition
+        let dragging = !matches!(input.mouse_button_left, ButtonState::Released(_));
+        if self.transition.is_some() && (dragging || input.mouse_wheel_delta != 0.0) {
+            self.transition = None;
+        }
+
         // Process Zoom Controls
         let zoom_sensitivity = self.zoom_sensitivity;
 
@@ -206,6 +614,9 @@ impl OrbitCameraController {
     }
 
     pub fn update_camera(&self, camera: &mut CameraProperties) {
+        camera.projection.set_orthographic(self.orthographic);
+        camera.projection.set_focus_distance(self.orbit_radius);
+
         camera.yaw = self.yaw;
         camera.pitch = self.pitch;
 
@@ -222,7 +633,10 @@ impl AsBindGroup for Camera {
     fn bind_group_layout_entries() -> Vec<wgpu::BindGroupLayoutEntry> {
         vec![wgpu::BindGroupLayoutEntry {
             binding: 0,
-            visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+            // Compute for the light cluster binning, where compute shaders are supported
+            visibility: wgpu::ShaderStages::VERTEX
+                | wgpu::ShaderStages::FRAGMENT
+                | wgpu::ShaderStages::COMPUTE,
             ty: wgpu::BindingType::Buffer {
                 ty: wgpu::BufferBindingType::Uniform,
                 // this is a generic Buffer, so we need to tell the layout if this
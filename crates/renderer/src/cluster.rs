@@ -0,0 +1,349 @@
+use crate::{
+    light::{LightManager, LightUniform, MAX_LIGHTS, MAX_STORAGE_LIGHTS},
+    post_process::{begin_pass, create_pipeline, uniform_entry},
+    sampler_cache::SamplerCache,
+    supports_compute, texture, wgpu_include_slang_shader,
+};
+
+/// Number of clusters across the screen, down the screen and in depth.
+/// This needs to match CLUSTER_COUNT_* in cluster.slang
+pub const CLUSTER_COUNT: [u32; 3] = [16, 9, 24];
+
+/// Every texel of the light masks has 128 bits, one per light
+pub const LIGHT_MASK_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Uint;
+pub const LIGHT_MASK_TEXTURE_COUNT: usize = MAX_LIGHTS / 128;
+/// 32 bit words in the light mask of every cluster of the storage buffer.
+/// This needs to match STORAGE_LIGHT_MASK_WORDS in cluster.slang
+pub const STORAGE_LIGHT_MASK_WORDS: u32 = MAX_STORAGE_LIGHTS as u32 / 32;
+
+/// Threads per workgroup of light-clusters-compute.slang in x and y
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Light masks of every cluster, with the depth slices side by side.
+/// Cluster `(x, y, z)` is at texel `(x + z * CLUSTER_COUNT[0], y)`. The grid
+/// only depends on the camera, so the masks don't need to be resized with the
+/// render.
+///
+/// Where compute shaders are supported, the masks are storage buffers that the
+/// compute binning writes, with room for [`MAX_STORAGE_LIGHTS`] lights. WebGL2
+/// has neither, so there the masks are render targets of a fragment shader
+/// instead, which only has room for [`MAX_LIGHTS`].
+pub enum ClusterMasks {
+    /// Every texture has a part of the mask of every cluster
+    Textures(Box<[texture::Texture; LIGHT_MASK_TEXTURE_COUNT]>),
+    Storage {
+        /// Every light, since the uniform light list only has room for
+        /// [`MAX_LIGHTS`]
+        lights: wgpu::Buffer,
+        /// [`STORAGE_LIGHT_MASK_WORDS`] per texel, texel after texel
+        masks: wgpu::Buffer,
+    },
+}
+
+impl ClusterMasks {
+    pub fn new(device: &wgpu::Device, sampler_cache: &SamplerCache) -> Self {
+        let [x, y, z] = CLUSTER_COUNT;
+
+        if !supports_compute(device) {
+            return Self::Textures(Box::new(std::array::from_fn(|index| {
+                texture::Texture::create_2d_texture(
+                    device,
+                    sampler_cache,
+                    x * z,
+                    y,
+                    LIGHT_MASK_FORMAT,
+                    wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+                    wgpu::FilterMode::Nearest,
+                    Some(&format!("Light Cluster Masks {index}")),
+                )
+            })));
+        }
+
+        Self::Storage {
+            lights: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Light Cluster Lights"),
+                size: (MAX_STORAGE_LIGHTS * size_of::<LightUniform>()) as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            masks: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Light Cluster Masks"),
+                size: (x * y * z * STORAGE_LIGHT_MASK_WORDS * 4) as u64,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }),
+        }
+    }
+
+    /// Number of lights the masks have a bit for.
+    pub fn max_lights(&self) -> usize {
+        match self {
+            Self::Textures(_) => MAX_LIGHTS,
+            Self::Storage { .. } => MAX_STORAGE_LIGHTS,
+        }
+    }
+
+    /// Fragment entry point of the lit and deferred lighting shaders that reads
+    /// these masks.
+    pub fn shading_entry_point(&self) -> &'static str {
+        match self {
+            Self::Textures(_) => "fs_main",
+            Self::Storage { .. } => "fs_main_storage",
+        }
+    }
+
+    /// Entries of the light bind group, after the light list at binding 0.
+    /// These need to match ClusteredLights in cluster.slang.
+    pub fn bind_group_layout_entries(&self) -> Vec<wgpu::BindGroupLayoutEntry> {
+        match self {
+            Self::Textures(textures) => (0..textures.len())
+                .map(|index| wgpu::BindGroupLayoutEntry {
+                    binding: index as u32 + 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Uint,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                })
+                .collect(),
+            Self::Storage { .. } => vec![
+                storage_entry(3, wgpu::ShaderStages::FRAGMENT, true),
+                storage_entry(4, wgpu::ShaderStages::FRAGMENT, true),
+            ],
+        }
+    }
+
+    pub fn bind_group_entries(&self) -> Vec<wgpu::BindGroupEntry<'_>> {
+        match self {
+            Self::Textures(textures) => textures
+                .iter()
+                .enumerate()
+                .map(|(index, texture)| wgpu::BindGroupEntry {
+                    binding: index as u32 + 1,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                })
+                .collect(),
+            Self::Storage { lights, masks } => vec![
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: lights.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: masks.as_entire_binding(),
+                },
+            ],
+        }
+    }
+}
+
+fn storage_entry(
+    binding: u32,
+    visibility: wgpu::ShaderStages,
+    read_only: bool,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+/// Clustered light culling. The view frustum is split into a grid of froxels,
+/// tiles of the screen that are sliced exponentially in depth. This pass tests
+/// the bounding sphere of every light against every froxel, and the lit and
+/// deferred lighting shaders then only evaluate the lights of the cluster
+/// their fragment is in.
+///
+/// Every cluster gets a bit mask of the lights that reach it, see
+/// [`ClusterMasks`]. Where compute shaders are supported, a compute shader
+/// bins the lights from a storage buffer. WebGL2 has no compute shaders or
+/// storage buffers, so there the binning falls back to a fragment shader over
+/// the light mask render targets, with the lights from the uniform light list.
+/// The two targets and the 16 KB uniform buffer limit of WebGL2 are what cap
+/// that fallback at [`MAX_LIGHTS`].
+pub enum LightClusterPipeline {
+    Fragment {
+        pipeline: wgpu::RenderPipeline,
+        /// Only has the light list, since the light bind group also has the
+        /// masks this renders into
+        bind_group: wgpu::BindGroup,
+    },
+    Compute {
+        pipeline: wgpu::ComputePipeline,
+        /// The light list for the light count, the lights and the masks this
+        /// writes, which the light bind group only has as read-only
+        bind_group: wgpu::BindGroup,
+    },
+}
+
+impl LightClusterPipeline {
+    pub fn new(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        light_manager: &LightManager,
+    ) -> Self {
+        match light_manager.cluster_masks() {
+            ClusterMasks::Textures(_) => {
+                Self::new_fragment(device, camera_bind_group_layout, light_manager)
+            }
+            ClusterMasks::Storage { lights, masks } => Self::new_compute(
+                device,
+                camera_bind_group_layout,
+                light_manager,
+                lights,
+                masks,
+            ),
+        }
+    }
+
+    fn new_fragment(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        light_manager: &LightManager,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Light Cluster Bind Group Layout"),
+            entries: &[uniform_entry(0)],
+        });
+
+        let target = Some(wgpu::ColorTargetState {
+            format: LIGHT_MASK_FORMAT,
+            blend: None,
+            write_mask: wgpu::ColorWrites::ALL,
+        });
+        let pipeline = create_pipeline(
+            device,
+            "Light Cluster Pipeline",
+            &[camera_bind_group_layout, &bind_group_layout],
+            wgpu_include_slang_shader!("light-clusters"),
+            &[target.clone(), target],
+        );
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light Cluster Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: light_manager.buffer().as_entire_binding(),
+            }],
+        });
+
+        Self::Fragment {
+            pipeline,
+            bind_group,
+        }
+    }
+
+    fn new_compute(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        light_manager: &LightManager,
+        lights: &wgpu::Buffer,
+        masks: &wgpu::Buffer,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Light Cluster Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ..uniform_entry(0)
+                },
+                storage_entry(1, wgpu::ShaderStages::COMPUTE, true),
+                storage_entry(2, wgpu::ShaderStages::COMPUTE, false),
+            ],
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Light Cluster Pipeline"),
+            bind_group_layouts: &[camera_bind_group_layout, &bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let module =
+            device.create_shader_module(wgpu_include_slang_shader!("light-clusters-compute"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Light Cluster Pipeline"),
+            layout: Some(&layout),
+            module: &module,
+            entry_point: Some("cs_main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light Cluster Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: light_manager.buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: lights.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: masks.as_entire_binding(),
+                },
+            ],
+        });
+
+        Self::Compute {
+            pipeline,
+            bind_group,
+        }
+    }
+
+    /// Assigns the lights to the clusters. This needs to run before anything
+    /// that reads the light bind group.
+    pub fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        camera_bind_group: &wgpu::BindGroup,
+        light_manager: &LightManager,
+    ) {
+        match self {
+            Self::Fragment {
+                pipeline,
+                bind_group,
+            } => {
+                let ClusterMasks::Textures(targets) = light_manager.cluster_masks() else {
+                    unreachable!("The fragment binning renders into the light mask textures");
+                };
+                let [low, high] = targets.as_ref();
+                let mut pass = begin_pass(encoder, "Light Cluster Pass", &[low, high]);
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, camera_bind_group, &[]);
+                pass.set_bind_group(1, bind_group, &[]);
+                pass.draw(0..3, 0..1);
+            }
+            Self::Compute {
+                pipeline,
+                bind_group,
+            } => {
+                let [x, y, z] = CLUSTER_COUNT;
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Light Cluster Pass"),
+                    timestamp_writes: None,
+                });
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, camera_bind_group, &[]);
+                pass.set_bind_group(1, bind_group, &[]);
+                // A thread per texel and word of its mask
+                pass.dispatch_workgroups(
+                    (x * z).div_ceil(WORKGROUP_SIZE),
+                    y.div_ceil(WORKGROUP_SIZE),
+                    STORAGE_LIGHT_MASK_WORDS,
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{
+    hdr::HDR_BUFFER_FORMAT,
+    light::LightManager,
+    post_process::{create_pipeline_with_entry_point, texture_entry},
+    sampler_cache::SamplerCache,
+    ssr, taa, texture, wgpu_include_slang_shader,
+    wgpu_traits::AsBindGroup,
+};
+
+/// Base color and alpha
+pub const BASE_COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+/// Octahedral world normal, perceptual roughness and metallic
+pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// Emitted light, and the ambient occlusion in alpha
+pub const EMISSIVE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Render targets of the G-buffer pass. WebGL only allows 4 color attachments,
+/// so the ARM texture is split over the normal and emissive targets, and the
+/// motion vectors go straight to TAA.
+pub const GBUFFER_FORMATS: [wgpu::TextureFormat; 4] = [
+    BASE_COLOR_FORMAT,
+    NORMAL_FORMAT,
+    EMISSIVE_FORMAT,
+    taa::VELOCITY_FORMAT,
+];
+
+/// How the meshes are lit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderPath {
+    /// The lit pass shades every fragment as it is drawn.
+    #[default]
+    Forward,
+    /// A G-buffer pass stores the surfaces, and a fullscreen pass shades every
+    /// pixel once.
+    Deferred,
+}
+
+/// Everything that depends on the size of the render.
+struct GBuffer {
+    base_color: texture::Texture,
+    normal: texture::Texture,
+    emissive: texture::Texture,
+    bind_group: wgpu::BindGroup,
+}
+
+/// The lighting pass of the deferred path, and the G-buffer it reads.
+///
+/// The G-buffer pass draws the meshes with the `fs_gbuffer` entry point of the
+/// lit shader, so both paths sample the materials the same way. The lighting
+/// pass then shades the G-buffer with the same lights and IBL as the forward
+/// lit pass, and writes the same outputs for the passes after it.
+pub struct DeferredPipeline {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    gbuffer: GBuffer,
+}
+
+impl DeferredPipeline {
+    /// `depth_view` is the depth buffer of the G-buffer pass. It needs to be
+    /// passed again to [`Self::resize`] whenever it is recreated.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        light_manager: &LightManager,
+        sky_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        // Every pixel is shaded once, so the G-buffer is only ever loaded
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("G-Buffer Bind Group Layout"),
+            entries: &[
+                texture_entry(0, false),
+                texture_entry(1, false),
+                texture_entry(2, false),
+                texture_entry(3, false),
+            ],
+        });
+
+        let targets = [
+            HDR_BUFFER_FORMAT,
+            ssr::NORMAL_ROUGHNESS_FORMAT,
+            ssr::SPECULAR_WEIGHT_FORMAT,
+        ]
+        .map(|format| {
+            Some(wgpu::ColorTargetState {
+                format,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })
+        });
+        let pipeline = create_pipeline_with_entry_point(
+            device,
+            "Deferred Lighting Pipeline",
+            &[
+                camera_bind_group_layout,
+                light_manager.bind_group_layout(),
+                sky_bind_group_layout,
+                &bind_group_layout,
+            ],
+            wgpu_include_slang_shader!("deferred-lighting"),
+            light_manager.cluster_masks().shading_entry_point(),
+            &targets,
+        );
+
+        let gbuffer = GBuffer::new(
+            device,
+            sampler_cache,
+            &bind_group_layout,
+            depth_view,
+            width,
+            height,
+        );
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            gbuffer,
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) {
+        self.gbuffer = GBuffer::new(
+            device,
+            sampler_cache,
+            &self.bind_group_layout,
+            depth_view,
+            width,
+            height,
+        );
+    }
+
+    /// Render targets of the G-buffer pass, in the order of
+    /// [`GBUFFER_FORMATS`] without the motion vectors.
+    pub fn gbuffer_views(&self) -> [&wgpu::TextureView; 3] {
+        [
+            &self.gbuffer.base_color.view,
+            &self.gbuffer.normal.view,
+            &self.gbuffer.emissive.view,
+        ]
+    }
+
+    /// Shades the G-buffer into a render pass with the HDR render, normal and
+    /// roughness, and specular weight targets of the lit pass. Pixels that the
+    /// G-buffer pass didn't cover are left as they are.
+    pub fn draw_lighting_in_render_pass(
+        &self,
+        render_pass: &mut wgpu::RenderPass,
+        camera_bind_group: &wgpu::BindGroup,
+        light_bind_group: &wgpu::BindGroup,
+        sky_bind_group: &wgpu::BindGroup,
+    ) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_bind_group(1, light_bind_group, &[]);
+        render_pass.set_bind_group(2, sky_bind_group, &[]);
+        render_pass.set_bind_group(3, &self.gbuffer.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+impl GBuffer {
+    fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let width = width.max(1);
+        let height = height.max(1);
+        let create_target = |format, label| {
+            texture::Texture::create_2d_texture(
+                device,
+                sampler_cache,
+                width,
+                height,
+                format,
+                wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+                wgpu::FilterMode::Nearest,
+                Some(label),
+            )
+        };
+
+        let base_color = create_target(BASE_COLOR_FORMAT, "G-Buffer Base Color");
+        let normal = create_target(NORMAL_FORMAT, "G-Buffer Normal");
+        let emissive = create_target(EMISSIVE_FORMAT, "G-Buffer Emissive");
+
+        let view = |binding, view| wgpu::BindGroupEntry {
+            binding,
+            resource: wgpu::BindingResource::TextureView(view),
+        };
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("G-Buffer Bind Group"),
+            layout: bind_group_layout,
+            entries: &[
+                view(0, &base_color.view),
+                view(1, &normal.view),
+                view(2, &emissive.view),
+                view(3, depth_view),
+            ],
+        });
+
+        Self {
+            base_color,
+            normal,
+            emissive,
+            bind_group,
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::sync::Arc;
+
+use cgmath::Angle;
+
+use crate::{
+    camera::CameraProperties,
+    hdr::HDR_BUFFER_FORMAT,
+    post_process::{begin_pass, create_pipeline, sampler_entry, texture_entry, uniform_entry},
+    sampler_cache::SamplerCache,
+    texture, wgpu_include_slang_shader,
+};
+
+/// What the lens is focused on.
+#[derive(Clone, Copy, Debug)]
+pub enum Focus {
+    /// Distance from the camera in meters
+    Distance(f32),
+    /// Whatever is in the centre of the screen, read from the depth buffer on
+    /// the GPU every frame.
+    Auto,
+}
+
+/// Settings of the depth of field. The focal length comes from the field of
+/// view of the camera and the aperture from its physical camera, so opening
+/// the aperture also brightens the image.
+pub struct DepthOfFieldProperties {
+    pub enabled: bool,
+    pub focus: Focus,
+    /// Height of the sensor in meters, which relates the field of view to the
+    /// focal length.
+    pub sensor_height: f32,
+    /// Largest radius of the blur in pixels. The cost of the blur doesn't
+    /// depend on it, but the gaps between its samples do.
+    pub max_coc: f32,
+}
+
+impl Default for DepthOfFieldProperties {
+    /// Autofocus with a full frame sensor, turned off.
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            focus: Focus::Auto,
+            sensor_height: 0.024,
+            max_coc: 24.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DepthOfFieldUniform {
+    focal_length: f32,
+    aperture: f32,
+    sensor_height: f32,
+    focus_distance: f32,
+    autofocus: f32,
+    max_coc: f32,
+    _padding: [f32; 2],
+}
+
+impl DepthOfFieldUniform {
+    fn new(properties: &DepthOfFieldProperties, camera: &CameraProperties) -> Self {
+        let half_fov_y = camera.projection.fov_y() / 2.0;
+        let (focus_distance, autofocus) = match properties.focus {
+            Focus::Distance(distance) => (distance, 0.0),
+            Focus::Auto => (0.0, 1.0),
+        };
+
+        Self {
+            focal_length: properties.sensor_height / (2.0 * half_fov_y.tan()),
+            aperture: camera.physical_camera.aperture,
+            sensor_height: properties.sensor_height,
+            focus_distance,
+            autofocus,
+            max_coc: properties.max_coc,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+/// Render targets of the passes and the bind groups reading them, which all
+/// depend on the size of the render.
+struct DepthOfFieldTargets {
+    /// Half resolution color with the CoC in alpha
+    prefiltered: texture::Texture,
+    far: texture::Texture,
+    near: texture::Texture,
+    /// Full resolution result, copied back into the HDR render texture
+    output: texture::Texture,
+    prefilter_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+    composite_bind_group: wgpu::BindGroup,
+}
+
+/// Bokeh depth of field, applied to the HDR render before tonemapping.
+///
+/// The render is downsampled to half resolution together with the signed circle
+/// of confusion (CoC) of every pixel. A gather blur then splits it into a
+/// blurred background and a blurred foreground, which get composited over the
+/// sharp render at full resolution. Everything uses render pipelines, so it
+/// also works on WebGL.
+pub struct DepthOfFieldPipeline {
+    prefilter_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    composite_pipeline: wgpu::RenderPipeline,
+    prefilter_bind_group_layout: wgpu::BindGroupLayout,
+    blur_bind_group_layout: wgpu::BindGroupLayout,
+    composite_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: Arc<wgpu::Sampler>,
+    uniform_buffer: wgpu::Buffer,
+    targets: DepthOfFieldTargets,
+    pub properties: DepthOfFieldProperties,
+}
+
+impl DepthOfFieldPipeline {
+    /// `color_view` and `depth_view` are the HDR render and depth buffer that
+    /// the depth of field reads from. They need to be passed again to
+    /// [`Self::resize`] whenever they are recreated.
+    pub fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let prefilter_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("DoF Prefilter Bind Group Layout"),
+                entries: &[
+                    uniform_entry(0),
+                    texture_entry(1, true),
+                    texture_entry(2, false),
+                    sampler_entry(3),
+                ],
+            });
+        let blur_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("DoF Blur Bind Group Layout"),
+                entries: &[uniform_entry(0), texture_entry(1, true), sampler_entry(2)],
+            });
+        let composite_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("DoF Composite Bind Group Layout"),
+                entries: &[
+                    uniform_entry(0),
+                    texture_entry(1, true),
+                    texture_entry(2, false),
+                    texture_entry(3, true),
+                    texture_entry(4, true),
+                    sampler_entry(5),
+                ],
+            });
+
+        let color_target = Some(wgpu::ColorTargetState {
+            format: HDR_BUFFER_FORMAT,
+            blend: None,
+            write_mask: wgpu::ColorWrites::ALL,
+        });
+
+        let prefilter_pipeline = create_pipeline(
+            device,
+            "DoF Prefilter Pipeline",
+            &[camera_bind_group_layout, &prefilter_bind_group_layout],
+            wgpu_include_slang_shader!("dof-prefilter"),
+            std::slice::from_ref(&color_target),
+        );
+        let blur_pipeline = create_pipeline(
+            device,
+            "DoF Blur Pipeline",
+            &[&blur_bind_group_layout],
+            wgpu_include_slang_shader!("dof-blur"),
+            &[color_target.clone(), color_target.clone()],
+        );
+        let composite_pipeline = create_pipeline(
+            device,
+            "DoF Composite Pipeline",
+            &[camera_bind_group_layout, &composite_bind_group_layout],
+            wgpu_include_slang_shader!("dof-composite"),
+            &[color_target],
+        );
+
+        let sampler = sampler_cache.get(
+            device,
+            &wgpu::SamplerDescriptor {
+                label: Some("DoF Sampler"),
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            },
+        );
+
+        let properties = DepthOfFieldProperties::default();
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("DoF Uniform Buffer"),
+            size: size_of::<DepthOfFieldUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let targets = DepthOfFieldTargets::new(
+            device,
+            sampler_cache,
+            &prefilter_bind_group_layout,
+            &blur_bind_group_layout,
+            &composite_bind_group_layout,
+            &sampler,
+            &uniform_buffer,
+            color_view,
+            depth_view,
+            width,
+            height,
+        );
+
+        Self {
+            prefilter_pipeline,
+            blur_pipeline,
+            composite_pipeline,
+            prefilter_bind_group_layout,
+            blur_bind_group_layout,
+            composite_bind_group_layout,
+            sampler,
+            uniform_buffer,
+            targets,
+            properties,
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) {
+        self.targets = DepthOfFieldTargets::new(
+            device,
+            sampler_cache,
+            &self.prefilter_bind_group_layout,
+            &self.blur_bind_group_layout,
+            &self.composite_bind_group_layout,
+            &self.sampler,
+            &self.uniform_buffer,
+            color_view,
+            depth_view,
+            width,
+            height,
+        );
+    }
+
+    /// Orthographic cameras have no lens, so they are always sharp.
+    pub fn is_active(&self, camera: &CameraProperties) -> bool {
+        self.properties.enabled && !camera.projection.is_orthographic()
+    }
+
+    pub fn queue_write_binding_resources(&self, queue: &wgpu::Queue, camera: &CameraProperties) {
+        let uniform = DepthOfFieldUniform::new(&self.properties, camera);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Blurs `color_texture` in place. It needs to be the texture the color
+    /// view passed to [`Self::new`] or [`Self::resize`] belongs to.
+    pub fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        camera_bind_group: &wgpu::BindGroup,
+        color_texture: &wgpu::Texture,
+    ) {
+        let targets = &self.targets;
+
+        {
+            let mut pass = begin_pass(encoder, "DoF Prefilter Pass", &[&targets.prefiltered]);
+            pass.set_pipeline(&self.prefilter_pipeline);
+            pass.set_bind_group(0, camera_bind_group, &[]);
+            pass.set_bind_group(1, &targets.prefilter_bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        {
+            let mut pass = begin_pass(encoder, "DoF Blur Pass", &[&targets.far, &targets.near]);
+            pass.set_pipeline(&self.blur_pipeline);
+            pass.set_bind_group(0, &targets.blur_bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        {
+            let mut pass = begin_pass(encoder, "DoF Composite Pass", &[&targets.output]);
+            pass.set_pipeline(&self.composite_pipeline);
+            pass.set_bind_group(0, camera_bind_group, &[]);
+            pass.set_bind_group(1, &targets.composite_bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        // The composite reads the render, so it can't render into it directly
+        encoder.copy_texture_to_texture(
+            targets.output.texture.as_image_copy(),
+            color_texture.as_image_copy(),
+            color_texture.size(),
+        );
+    }
+}
+
+impl DepthOfFieldTargets {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        prefilter_bind_group_layout: &wgpu::BindGroupLayout,
+        blur_bind_group_layout: &wgpu::BindGroupLayout,
+        composite_bind_group_layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        uniform_buffer: &wgpu::Buffer,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let half_width = width.div_ceil(2).max(1);
+        let half_height = height.div_ceil(2).max(1);
+        let create_target = |width, height, usage, label| {
+            texture::Texture::create_2d_texture(
+                device,
+                sampler_cache,
+                width,
+                height,
+                HDR_BUFFER_FORMAT,
+                wgpu::TextureUsages::RENDER_ATTACHMENT | usage,
+                wgpu::FilterMode::Linear,
+                Some(label),
+            )
+        };
+
+        let binding = wgpu::TextureUsages::TEXTURE_BINDING;
+        let prefiltered = create_target(half_width, half_height, binding, "DoF Prefiltered");
+        let far = create_target(half_width, half_height, binding, "DoF Far Field");
+        let near = create_target(half_width, half_height, binding, "DoF Near Field");
+        let output = create_target(
+            width.max(1),
+            height.max(1),
+            wgpu::TextureUsages::COPY_SRC,
+            "DoF Output",
+        );
+
+        let uniform = wgpu::BindGroupEntry {
+            binding: 0,
+            resource: uniform_buffer.as_entire_binding(),
+        };
+        let view = |binding, view| wgpu::BindGroupEntry {
+            binding,
+            resource: wgpu::BindingResource::TextureView(view),
+        };
+        let sampler = |binding| wgpu::BindGroupEntry {
+            binding,
+            resource: wgpu::BindingResource::Sampler(sampler),
+        };
+
+        let prefilter_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("DoF Prefilter Bind Group"),
+            layout: prefilter_bind_group_layout,
+            entries: &[
+                uniform.clone(),
+                view(1, color_view),
+                view(2, depth_view),
+                sampler(3),
+            ],
+        });
+        let blur_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("DoF Blur Bind Group"),
+            layout: blur_bind_group_layout,
+            entries: &[uniform.clone(), view(1, &prefiltered.view), sampler(2)],
+        });
+        let composite_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("DoF Composite Bind Group"),
+            layout: composite_bind_group_layout,
+            entries: &[
+                uniform,
+                view(1, color_view),
+                view(2, depth_view),
+                view(3, &far.view),
+                view(4, &near.view),
+                sampler(5),
+            ],
+        });
+
+        Self {
+            prefiltered,
+            far,
+            near,
+            output,
+            prefilter_bind_group,
+            blur_bind_group,
+            composite_bind_group,
+        }
+    }
+}
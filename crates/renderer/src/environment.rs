@@ -0,0 +1,678 @@
+use std::sync::Arc;
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    procedural_sky::PreethamUniform, sampler_cache::SamplerCache, sky::ShCoefficients,
+    supports_compute, texture, wgpu_include_slang_shader,
+};
+
+/// Faces bigger than this would not add visible detail to the reflections, but
+/// would make prefiltering noticeably slower.
+const MAX_FACE_SIZE: u32 = 512;
+const MIN_FACE_SIZE: u32 = 16;
+/// The procedural sky is smooth apart from the sun, which is left out of the
+/// environment, so a small cube is enough.
+const PROCEDURAL_FACE_SIZE: u32 = 128;
+/// The SH projection is integrated over a mip with at most this many texels
+/// per face edge. Irradiance is so low frequency that more texels don't help.
+const SH_FACE_SIZE: u32 = 64;
+const PREFILTER_SAMPLE_COUNT: u32 = 512;
+/// Needs to match the numthreads of all env-*.slang shaders
+const WORKGROUP_SIZE: u32 = 8;
+/// Needs to match SUM_COUNT in env-sh.slang
+const SH_SUM_COUNT: usize = 10;
+/// Bytes of a single Rgba16Float texel
+const TEXEL_SIZE: u32 = 8;
+const ENVIRONMENT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Convolution and normalization constants of the 9 SH coefficients, in the
+/// same order as the coefficients. They fold together the clamped cosine lobe
+/// (π, 2π/3, π/4 per band) and the squared normalization factors, which is what
+/// `sh-coefficient-baker` applies when computing irradiance.
+const SH_IRRADIANCE_SCALE: [f32; 9] = [
+    1.0 / 4.0,
+    1.0 / 2.0,
+    1.0 / 2.0,
+    1.0 / 2.0,
+    5.0 / 192.0,
+    5.0 / 48.0,
+    5.0 / 16.0,
+    5.0 / 48.0,
+    5.0 / 192.0,
+];
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct EnvironmentBakeUniform {
+    face_size: u32,
+    row_stride: u32,
+    sample_count: u32,
+    roughness: f32,
+    source_face_size: f32,
+    source_mip_count: f32,
+    _padding: [f32; 2],
+}
+
+/// This needs to match ProceduralSkyParameters in env-procedural-sky.slang
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ProceduralSkyBakeUniform {
+    bake: EnvironmentBakeUniform,
+    sky: PreethamUniform,
+}
+
+struct BakePipeline {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl BakePipeline {
+    fn new(
+        device: &wgpu::Device,
+        label: &str,
+        source_view_dimension: Option<wgpu::TextureViewDimension>,
+        with_sampler: bool,
+        shader: wgpu::ShaderModuleDescriptor,
+    ) -> Self {
+        let mut entries = vec![wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }];
+        if let Some(view_dimension) = source_view_dimension {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding: entries.len() as u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension,
+                    multisampled: false,
+                },
+                count: None,
+            });
+        }
+        if with_sampler {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding: entries.len() as u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            });
+        }
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: entries.len() as u32,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(&format!("{label} Bind Group Layout")),
+            entries: &entries,
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&format!("{label} Pipeline Layout")),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let module = device.create_shader_module(shader);
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(label),
+            layout: Some(&layout),
+            module: &module,
+            entry_point: Some("cs_main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            bind_group_layout,
+        }
+    }
+
+    /// Records a compute pass that runs one thread per texel of a cube mip.
+    /// `resources` are bound right after the uniform, and `output` last.
+    fn dispatch<U: bytemuck::Pod>(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        uniform: U,
+        resources: &[wgpu::BindingResource],
+        output: &wgpu::Buffer,
+        workgroups: u32,
+    ) {
+        // Every pass gets its own uniform buffer. Writing to a shared one with
+        // queue.write_buffer would make all passes of the encoder see the last value
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Bake Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: uniform_buffer.as_entire_binding(),
+        }];
+        for resource in resources {
+            entries.push(wgpu::BindGroupEntry {
+                binding: entries.len() as u32,
+                resource: resource.clone(),
+            });
+        }
+        entries.push(wgpu::BindGroupEntry {
+            binding: entries.len() as u32,
+            resource: output.as_entire_binding(),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment Bake Bind Group"),
+            layout: &self.bind_group_layout,
+            entries: &entries,
+        });
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Environment Bake Pass"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(workgroups, workgroups, 6);
+    }
+}
+
+/// Number of texels in a row of the staging buffer. Rows are padded so the
+/// buffer can be copied into a texture directly.
+fn row_stride(face_size: u32) -> u32 {
+    (face_size * TEXEL_SIZE).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) / TEXEL_SIZE
+}
+
+fn workgroup_count(face_size: u32) -> u32 {
+    face_size.div_ceil(WORKGROUP_SIZE)
+}
+
+/// Generates everything [`crate::sky::SkyPipeline`] needs for image based
+/// lighting from a raw HDR environment, on the GPU with compute shaders:
+/// - a cubemap with a GGX prefiltered mip chain for specular reflections
+/// - SH coefficients of the irradiance for diffuse lighting
+///
+/// This produces the same data as `cubemap-ktx2-baker` and
+/// `sh-coefficient-baker` do offline, so any HDRI can be used without baking it
+/// first. Compute shaders are not available on WebGL, so
+/// [`EnvironmentBaker::new`] fails there and the baked files have to be used.
+pub struct EnvironmentBaker {
+    equirect_pipeline: BakePipeline,
+    downsample_pipeline: BakePipeline,
+    prefilter_pipeline: BakePipeline,
+    sh_pipeline: BakePipeline,
+    procedural_pipeline: BakePipeline,
+    sampler: Arc<wgpu::Sampler>,
+    /// Sampler of the baked cubemaps
+    cube_sampler: Arc<wgpu::Sampler>,
+}
+
+impl EnvironmentBaker {
+    pub fn new(device: &wgpu::Device, sampler_cache: &SamplerCache) -> anyhow::Result<Self> {
+        if !supports_compute(device) {
+            bail!("Generating environment maps requires compute shader support.");
+        }
+
+        let equirect_pipeline = BakePipeline::new(
+            device,
+            "Equirectangular To Cube",
+            Some(wgpu::TextureViewDimension::D2),
+            true,
+            wgpu_include_slang_shader!("env-equirect-to-cube"),
+        );
+        let downsample_pipeline = BakePipeline::new(
+            device,
+            "Environment Downsample",
+            Some(wgpu::TextureViewDimension::D2Array),
+            true,
+            wgpu_include_slang_shader!("env-downsample"),
+        );
+        let prefilter_pipeline = BakePipeline::new(
+            device,
+            "Environment Prefilter",
+            Some(wgpu::TextureViewDimension::Cube),
+            true,
+            wgpu_include_slang_shader!("env-prefilter"),
+        );
+        let sh_pipeline = BakePipeline::new(
+            device,
+            "Environment SH Projection",
+            Some(wgpu::TextureViewDimension::D2Array),
+            false,
+            wgpu_include_slang_shader!("env-sh"),
+        );
+        let procedural_pipeline = BakePipeline::new(
+            device,
+            "Procedural Sky To Cube",
+            None,
+            false,
+            wgpu_include_slang_shader!("env-procedural-sky"),
+        );
+
+        // Repeat horizontally so that equirectangular images wrap around
+        // seamlessly. Cube views ignore the address modes.
+        let sampler = sampler_cache.get(
+            device,
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::Repeat,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            },
+        );
+        let cube_sampler = sampler_cache.get(
+            device,
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            },
+        );
+
+        Ok(Self {
+            equirect_pipeline,
+            downsample_pipeline,
+            prefilter_pipeline,
+            sh_pipeline,
+            procedural_pipeline,
+            sampler,
+            cube_sampler,
+        })
+    }
+
+    /// Converts an equirectangular (latitude/longitude) HDR image into a
+    /// prefiltered environment cubemap and its irradiance SH coefficients.
+    pub fn bake_equirect(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &image::Rgba32FImage,
+        label: Option<&str>,
+    ) -> anyhow::Result<(texture::Texture, ShCoefficients)> {
+        let equirect_texture = upload_equirect(device, queue, image, label);
+        let equirect_view = equirect_texture.create_view(&Default::default());
+
+        // Match the resolution of the image around the horizon
+        let face_size = 1 << (image.width() / 4).max(1).ilog2();
+        let face_size = face_size.clamp(MIN_FACE_SIZE, MAX_FACE_SIZE);
+
+        let radiance = create_cube_texture(device, Some("Environment Radiance Cube"), face_size);
+        let staging = create_staging_buffer(device, face_size);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Equirectangular To Cube Encoder"),
+        });
+
+        self.equirect_pipeline.dispatch(
+            device,
+            &mut encoder,
+            EnvironmentBakeUniform {
+                face_size,
+                row_stride: row_stride(face_size),
+                ..bytemuck::Zeroable::zeroed()
+            },
+            &[
+                wgpu::BindingResource::TextureView(&equirect_view),
+                wgpu::BindingResource::Sampler(&self.sampler),
+            ],
+            &staging,
+            workgroup_count(face_size),
+        );
+        copy_staging_to_cube_mip(&mut encoder, &staging, &radiance, 0);
+        queue.submit(Some(encoder.finish()));
+
+        self.bake_radiance_cube(device, queue, &radiance, label)
+    }
+
+    /// Renders the procedural sky into a prefiltered environment cubemap and
+    /// its irradiance SH coefficients, so that it lights the scene like any
+    /// other environment.
+    pub fn bake_procedural(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sky: PreethamUniform,
+        label: Option<&str>,
+    ) -> anyhow::Result<(texture::Texture, ShCoefficients)> {
+        let face_size = PROCEDURAL_FACE_SIZE;
+        let radiance = create_cube_texture(device, Some("Procedural Sky Radiance Cube"), face_size);
+        let staging = create_staging_buffer(device, face_size);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Procedural Sky To Cube Encoder"),
+        });
+
+        self.procedural_pipeline.dispatch(
+            device,
+            &mut encoder,
+            ProceduralSkyBakeUniform {
+                bake: EnvironmentBakeUniform {
+                    face_size,
+                    row_stride: row_stride(face_size),
+                    ..bytemuck::Zeroable::zeroed()
+                },
+                sky,
+            },
+            &[],
+            &staging,
+            workgroup_count(face_size),
+        );
+        copy_staging_to_cube_mip(&mut encoder, &staging, &radiance, 0);
+        queue.submit(Some(encoder.finish()));
+
+        self.bake_radiance_cube(device, queue, &radiance, label)
+    }
+
+    /// Prefilters a radiance cube whose mip 0 has already been written. This
+    /// fills in the rest of its mip chain, which is then used to prefilter
+    /// every roughness level and to compute the SH coefficients.
+    fn bake_radiance_cube(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        radiance: &wgpu::Texture,
+        label: Option<&str>,
+    ) -> anyhow::Result<(texture::Texture, ShCoefficients)> {
+        let face_size = radiance.width();
+        let mip_count = radiance.mip_level_count();
+        let staging = create_staging_buffer(device, face_size);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Bake Encoder"),
+        });
+
+        // Box filtered mip chain of the radiance. Passes are recorded in order,
+        // so each one reads the mip that was copied in by the previous one
+        for mip in 1..mip_count {
+            let source_view = mip_array_view(radiance, mip - 1);
+            let size = face_size >> mip;
+            self.downsample_pipeline.dispatch(
+                device,
+                &mut encoder,
+                EnvironmentBakeUniform {
+                    face_size: size,
+                    row_stride: row_stride(size),
+                    ..bytemuck::Zeroable::zeroed()
+                },
+                &[
+                    wgpu::BindingResource::TextureView(&source_view),
+                    wgpu::BindingResource::Sampler(&self.sampler),
+                ],
+                &staging,
+                workgroup_count(size),
+            );
+            copy_staging_to_cube_mip(&mut encoder, &staging, radiance, mip);
+        }
+
+        // GGX prefiltered mips, with the same roughness per mip as cubemap-ktx2-baker
+        let prefiltered = create_cube_texture(device, label, face_size);
+        let radiance_view = radiance.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let roughness_gap = 1.0 / (mip_count + 1) as f32;
+        for mip in 0..mip_count {
+            let size = face_size >> mip;
+            self.prefilter_pipeline.dispatch(
+                device,
+                &mut encoder,
+                EnvironmentBakeUniform {
+                    face_size: size,
+                    row_stride: row_stride(size),
+                    sample_count: PREFILTER_SAMPLE_COUNT,
+                    roughness: roughness_gap * mip as f32,
+                    source_face_size: face_size as f32,
+                    source_mip_count: mip_count as f32,
+                    _padding: [0.0; 2],
+                },
+                &[
+                    wgpu::BindingResource::TextureView(&radiance_view),
+                    wgpu::BindingResource::Sampler(&self.sampler),
+                ],
+                &staging,
+                workgroup_count(size),
+            );
+            copy_staging_to_cube_mip(&mut encoder, &staging, &prefiltered, mip);
+        }
+
+        // SH projection of the radiance
+        let sh_mip = face_size.ilog2().saturating_sub(SH_FACE_SIZE.ilog2());
+        let sh_size = face_size >> sh_mip;
+        let sh_view = mip_array_view(radiance, sh_mip);
+        let group_count = workgroup_count(sh_size);
+        let sums_size = (group_count * group_count * 6) as u64
+            * (SH_SUM_COUNT * std::mem::size_of::<[f32; 4]>()) as u64;
+        let sums_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Environment SH Sums Buffer"),
+            size: sums_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Environment SH Readback Buffer"),
+            size: sums_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.sh_pipeline.dispatch(
+            device,
+            &mut encoder,
+            EnvironmentBakeUniform {
+                face_size: sh_size,
+                ..bytemuck::Zeroable::zeroed()
+            },
+            &[wgpu::BindingResource::TextureView(&sh_view)],
+            &sums_buffer,
+            group_count,
+        );
+        encoder.copy_buffer_to_buffer(&sums_buffer, 0, &readback_buffer, 0, sums_size);
+
+        queue.submit(Some(encoder.finish()));
+
+        let sh_coefficients = read_sh_coefficients(device, &readback_buffer)?;
+
+        let view = prefiltered.create_view(&wgpu::TextureViewDescriptor {
+            label,
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        Ok((
+            texture::Texture {
+                texture: prefiltered,
+                view,
+                sampler: self.cube_sampler.clone(),
+            },
+            sh_coefficients,
+        ))
+    }
+}
+
+/// Uploads the image as an Rgba16Float texture, since 32 bit float textures
+/// cannot be filtered without an extra feature. Images that are larger than
+/// the device allows are scaled down first.
+fn upload_equirect(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    image: &image::Rgba32FImage,
+    label: Option<&str>,
+) -> wgpu::Texture {
+    let max_dimension = device.limits().max_texture_dimension_2d;
+    let resized;
+    let image = if image.width() > max_dimension || image.height() > max_dimension {
+        let scale = max_dimension as f32 / image.width().max(image.height()) as f32;
+        let width = ((image.width() as f32 * scale) as u32).max(1);
+        let height = ((image.height() as f32 * scale) as u32).max(1);
+        log::warn!(
+            "Environment image is {}x{}, which is larger than the maximum texture size. Scaling it down to {width}x{height}.",
+            image.width(),
+            image.height()
+        );
+        resized =
+            image::imageops::resize(image, width, height, image::imageops::FilterType::Triangle);
+        &resized
+    } else {
+        image
+    };
+
+    let data = image
+        .as_raw()
+        .iter()
+        .map(|value| half::f16::from_f32(*value).to_bits())
+        .collect::<Vec<_>>();
+
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label,
+            size: wgpu::Extent3d {
+                width: image.width(),
+                height: image.height(),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: ENVIRONMENT_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&data),
+    )
+}
+
+fn create_cube_texture(
+    device: &wgpu::Device,
+    label: Option<&str>,
+    face_size: u32,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label,
+        size: wgpu::Extent3d {
+            width: face_size,
+            height: face_size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count: face_size.ilog2() + 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: ENVIRONMENT_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+/// Buffer that the compute shaders write texels into, big enough for all 6
+/// faces of the mip 0 of a cube. Storage textures would avoid the extra copy,
+/// but Rgba16Float is only writable from shaders on some backends.
+fn create_staging_buffer(device: &wgpu::Device, face_size: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Environment Staging Buffer"),
+        size: (row_stride(face_size) * face_size * 6 * TEXEL_SIZE) as u64,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
+
+fn copy_staging_to_cube_mip(
+    encoder: &mut wgpu::CommandEncoder,
+    staging: &wgpu::Buffer,
+    cube: &wgpu::Texture,
+    mip_level: u32,
+) {
+    let size = cube.width() >> mip_level;
+    encoder.copy_buffer_to_texture(
+        wgpu::TexelCopyBufferInfo {
+            buffer: staging,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(row_stride(size) * TEXEL_SIZE),
+                rows_per_image: Some(size),
+            },
+        },
+        wgpu::TexelCopyTextureInfo {
+            texture: cube,
+            mip_level,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+    );
+}
+
+/// View of a single mip of a cube as an array of its 6 faces.
+fn mip_array_view(cube: &wgpu::Texture, mip_level: u32) -> wgpu::TextureView {
+    cube.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        base_mip_level: mip_level,
+        mip_level_count: Some(1),
+        ..Default::default()
+    })
+}
+
+/// Waits for the SH projection to finish and adds up the partial sums of all
+/// workgroups. This blocks, which is fine since it only happens while loading.
+fn read_sh_coefficients(
+    device: &wgpu::Device,
+    readback_buffer: &wgpu::Buffer,
+) -> anyhow::Result<ShCoefficients> {
+    let slice = readback_buffer.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::PollType::Wait)?;
+    receiver.recv()??;
+
+    let mut sums = [[0.0f64; 3]; SH_SUM_COUNT];
+    {
+        let data = slice.get_mapped_range();
+        let partial_sums: &[[f32; 4]] = bytemuck::cast_slice(&data);
+        for group in partial_sums.chunks_exact(SH_SUM_COUNT) {
+            for (sum, partial) in sums.iter_mut().zip(group) {
+                sum[0] += partial[0] as f64;
+                sum[1] += partial[1] as f64;
+                sum[2] += partial[2] as f64;
+            }
+        }
+    }
+    readback_buffer.unmap();
+
+    // The last sum is the total solid angle, which should be close to 4π
+    let total_weight = sums[SH_SUM_COUNT - 1][0];
+    let normalization = 4.0 * std::f64::consts::PI / total_weight;
+
+    Ok(sums
+        .iter()
+        .zip(SH_IRRADIANCE_SCALE)
+        .map(|(sum, scale)| sum.map(|value| (value * normalization) as f32 * scale))
+        .collect())
+}
@@ -1,7 +1,9 @@
-use wgpu::{Operations, util::DeviceExt};
+use wgpu::Operations;
 
 use crate::{
-    create_render_pipeline, resources,
+    create_render_pipeline,
+    resources::{self, ResourceError},
+    sampler_cache::SamplerCache,
     texture::{self, TextureImportOptions},
     wgpu_include_slang_shader,
     wgpu_traits::AsBindGroup,
@@ -16,50 +18,19 @@ pub struct HdrPipeline {
     pipeline: wgpu::RenderPipeline,
     bind_group_layout: wgpu::BindGroupLayout,
     bind_group: Option<wgpu::BindGroup>,
-    uniform_buffer: Option<wgpu::Buffer>,
     render_texture: Option<texture::Texture>,
     display_view_lut_texture: texture::Texture,
     width: u32,
     height: u32,
-    pub properties: HdrViewProperties,
-    view_uniform: HdrViewUniform,
-}
-
-pub struct HdrViewProperties {
-    pub exposure_ev: f32,
-}
-
-impl Default for HdrViewProperties {
-    fn default() -> Self {
-        Self { exposure_ev: 0.0 }
-    }
-}
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct HdrViewUniform {
-    pub exposure_linear: f32,
-    // I don't quite understand this.. I needed to make this a 32 byte struct
-    // because the struct in the shader expects 32 bytes, *even though its
-    // just made up of a f32 and a vec3<f32>*. Shouldn't that be 16 bytes!?
-    pub _padding: [f32; 7],
-}
-
-impl From<&HdrViewProperties> for HdrViewUniform {
-    fn from(value: &HdrViewProperties) -> Self {
-        Self {
-            exposure_linear: f32::powf(2.0, value.exposure_ev),
-            _padding: [0.0; 7],
-        }
-    }
 }
 
 impl HdrPipeline {
     pub async fn new(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
         config: &wgpu::SurfaceConfiguration,
-    ) -> Self {
+    ) -> Result<Self, ResourceError> {
         let width = config.width.max(1);
         let height = config.height.max(1);
 
@@ -67,12 +38,14 @@ impl HdrPipeline {
             "shaper_to_displayP3_48.ktx2",
             device,
             queue,
+            sampler_cache,
+            None,
             TextureImportOptions {
                 label: Some("Display View LUT"),
+                ..Default::default()
             },
         )
-        .await
-        .unwrap();
+        .await?;
 
         let bind_group_layout =
             Self::create_bind_group_layout(device, "HDR Pipeline Bind Group Layout");
@@ -87,40 +60,41 @@ impl HdrPipeline {
         let pipeline = create_render_pipeline(
             device,
             &pipeline_layout,
-            config.format,
+            &[config.format],
             None,
             // We'll use some math to generate the vertex data in
             // the shader, so we don't need any vertex buffers
             &[],
             wgpu::PrimitiveTopology::TriangleList,
             shader,
+            "fs_main",
         );
 
-        let properties = HdrViewProperties::default();
-        let view_uniform: HdrViewUniform = (&properties).into();
-
         let mut hdr_pipeline = Self {
             pipeline,
             bind_group_layout,
             width,
             height,
             bind_group: None,
-            render_texture: None,
-            properties,
-            view_uniform,
-            uniform_buffer: None,
+            render_texture: Some(create_render_texture(device, sampler_cache, width, height)),
             display_view_lut_texture: display_view,
         };
 
         hdr_pipeline.init_all(device);
 
-        hdr_pipeline
+        Ok(hdr_pipeline)
     }
 
-    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        width: u32,
+        height: u32,
+    ) {
         self.width = width;
         self.height = height;
-        self.init_binding_resources(device);
+        self.render_texture = Some(create_render_texture(device, sampler_cache, width, height));
         self.init_bind_group(device);
     }
 
@@ -158,20 +132,12 @@ impl HdrPipeline {
         self.render_texture.as_ref().unwrap()
     }
 
-    pub fn texture_view(&self) -> &wgpu::TextureView {
-        &self.render_texture().view
+    pub fn texture(&self) -> &wgpu::Texture {
+        &self.render_texture().texture
     }
 
-    pub fn texture_format(&self) -> wgpu::TextureFormat {
-        self.render_texture().texture.format()
-    }
-
-    pub fn uniform_buffer(&self) -> &wgpu::Buffer {
-        if self.uniform_buffer.is_none() {
-            panic!("Uniform Buffer for HDR Pipeline has not been initialized!");
-        }
-
-        self.uniform_buffer.as_ref().unwrap()
+    pub fn texture_view(&self) -> &wgpu::TextureView {
+        &self.render_texture().view
     }
 }
 
@@ -181,16 +147,6 @@ impl AsBindGroup for HdrPipeline {
             wgpu::BindGroupLayoutEntry {
                 binding: 0,
                 visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            wgpu::BindGroupLayoutEntry {
-                binding: 1,
-                visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     sample_type: wgpu::TextureSampleType::Float { filterable: true },
                     view_dimension: wgpu::TextureViewDimension::D2,
@@ -199,13 +155,13 @@ impl AsBindGroup for HdrPipeline {
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 2,
+                binding: 1,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 3,
+                binding: 2,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     multisampled: false,
@@ -215,7 +171,7 @@ impl AsBindGroup for HdrPipeline {
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 4,
+                binding: 3,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 // This should match the filterable field of the
                 // corresponding Texture entry above.
@@ -232,24 +188,20 @@ impl AsBindGroup for HdrPipeline {
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: self.uniform_buffer().as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
                     resource: wgpu::BindingResource::TextureView(&self.render_texture().view),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 2,
+                    binding: 1,
                     resource: wgpu::BindingResource::Sampler(&self.render_texture().sampler),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 3,
+                    binding: 2,
                     resource: wgpu::BindingResource::TextureView(
                         &self.display_view_lut_texture.view,
                     ),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 4,
+                    binding: 3,
                     resource: wgpu::BindingResource::Sampler(
                         &self.display_view_lut_texture.sampler,
                     ),
@@ -258,25 +210,9 @@ impl AsBindGroup for HdrPipeline {
         }));
     }
 
-    fn init_binding_resources(&mut self, device: &wgpu::Device) {
-        self.render_texture = Some(texture::Texture::create_2d_texture(
-            device,
-            self.width,
-            self.height,
-            HDR_BUFFER_FORMAT,
-            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            wgpu::FilterMode::Nearest,
-            Some("HDR Pipeline Texture"),
-        ));
-
-        self.uniform_buffer = Some(
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("View Uniform Buffer"),
-                contents: bytemuck::cast_slice(&[self.view_uniform]),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            }),
-        );
-    }
+    // The render texture is the only resource, and it is created with the
+    // size of the render in `new` and `resize`
+    fn init_binding_resources(&mut self, _device: &wgpu::Device) {}
 
     fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
         &self.bind_group_layout
@@ -290,17 +226,31 @@ impl AsBindGroup for HdrPipeline {
         self.bind_group.as_ref().unwrap()
     }
 
-    fn update_binding_resources(&mut self) {
-        self.view_uniform = (&self.properties).into();
-    }
+    // Exposure is applied by the passes that write the render, so tonemapping
+    // has no settings
+    fn update_binding_resources(&mut self) {}
 
-    fn queue_write_binding_resources(&mut self, queue: &wgpu::Queue) {
-        self.update_binding_resources();
+    fn queue_write_binding_resources(&mut self, _queue: &wgpu::Queue) {}
+}
 
-        queue.write_buffer(
-            self.uniform_buffer(),
-            0,
-            bytemuck::cast_slice(&[self.view_uniform]),
-        );
-    }
+fn create_render_texture(
+    device: &wgpu::Device,
+    sampler_cache: &SamplerCache,
+    width: u32,
+    height: u32,
+) -> texture::Texture {
+    texture::Texture::create_2d_texture(
+        device,
+        sampler_cache,
+        width,
+        height,
+        HDR_BUFFER_FORMAT,
+        // Copied from for screenshots, and into by the depth of field
+        wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::COPY_SRC
+            | wgpu::TextureUsages::COPY_DST,
+        wgpu::FilterMode::Nearest,
+        Some("HDR Pipeline Texture"),
+    )
 }
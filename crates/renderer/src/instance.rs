@@ -1,34 +1,31 @@
+use std::ops::Range;
+
 use cgmath::{Matrix, SquareMatrix};
 
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
 }
 
 impl Instance {
     pub fn to_raw(&self) -> InstanceRaw {
         let translation = cgmath::Matrix4::from_translation(self.position);
         let rotation = cgmath::Matrix4::from(self.rotation);
-        let scale = cgmath::Matrix4::from_nonuniform_scale(1.0, 1.0, 1.0);
-
-        let rotation_scale = rotation * scale;
-
-        let truncated = cgmath::Matrix3::from_cols(
-            rotation_scale.x.truncate(), // Vector4 → Vector3
-            rotation_scale.y.truncate(),
-            rotation_scale.z.truncate(),
-        );
+        let scale =
+            cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z);
 
-        let normal_matrix = truncated.transpose().invert().unwrap();
+        InstanceRaw::from_model_matrix(translation * rotation * scale)
+    }
+}
 
-        // We tranpose *again* before converting to the GPU representation because
-        // we need the GPU representation to be in *column-major* layout.
-        // However, this is very odd because cgmath matrices are already in column-major
-        // layout. So why are we having to tranpose for it to work..? Is there something
-        // weird going on with how slang gets converted to wgsl?
-        InstanceRaw {
-            model: (translation * rotation_scale).into(),
-            normal: normal_matrix.into(),
+impl Default for Instance {
+    fn default() -> Self {
+        use cgmath::{One, Zero};
+        Self {
+            position: cgmath::Vector3::zero(),
+            rotation: cgmath::Quaternion::one(),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
         }
     }
 }
@@ -44,10 +41,14 @@ pub struct InstanceRaw {
     // This leads to incorrect normals whenever we have scaling involved. We actually
     // need to transform our normals by the "inverse transpose" of the model matrix.
     normal: [[f32; 3]; 3],
+    // Model matrix of the previous frame, for motion vectors
+    previous_model: [[f32; 4]; 4],
 }
 
 impl InstanceRaw {
-    const ATTRIBUTES: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+    // Together with the 5 attributes of the vertices, this uses all 16 vertex
+    // attributes that WebGL guarantees
+    const ATTRIBUTES: [wgpu::VertexAttribute; 11] = wgpu::vertex_attr_array![
         5 => Float32x4,
         6 => Float32x4,
         7 => Float32x4,
@@ -55,6 +56,10 @@ impl InstanceRaw {
         9 => Float32x3,
         10 => Float32x3,
         11 => Float32x3,
+        12 => Float32x4,
+        13 => Float32x4,
+        14 => Float32x4,
+        15 => Float32x4,
     ];
 
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
@@ -65,4 +70,148 @@ impl InstanceRaw {
             attributes: &Self::ATTRIBUTES,
         }
     }
+
+    pub fn from_model_matrix(model: cgmath::Matrix4<f32>) -> Self {
+        let truncated = cgmath::Matrix3::from_cols(
+            model.x.truncate(), // Vector4 → Vector3
+            model.y.truncate(),
+            model.z.truncate(),
+        );
+
+        // A zero scale on any axis makes the matrix non-invertible. We fall back
+        // to the identity so the instance simply gets flat shading instead of NaNs.
+        let normal_matrix = truncated
+            .transpose()
+            .invert()
+            .unwrap_or_else(cgmath::Matrix3::identity);
+
+        // We tranpose *again* before converting to the GPU representation because
+        // we need the GPU representation to be in *column-major* layout.
+        // However, this is very odd because cgmath matrices are already in column-major
+        // layout. So why are we having to tranpose for it to work..? Is there something
+        // weird going on with how slang gets converted to wgsl?
+        Self {
+            model: model.into(),
+            normal: normal_matrix.into(),
+            previous_model: model.into(),
+        }
+    }
+
+    /// Sets where the instance was in the previous frame, which is where it
+    /// is now by default.
+    pub fn with_previous_model_matrix(mut self, previous_model: cgmath::Matrix4<f32>) -> Self {
+        self.previous_model = previous_model.into();
+        self
+    }
+}
+
+/// A growable GPU vertex buffer of [`InstanceRaw`]s.
+///
+/// Instances can be modified, added or removed at any time. Modifications are only
+/// recorded on the CPU side and the range of instances that changed is tracked, so
+/// that [`InstanceBuffer::queue_write`] only re-uploads the dirty range. If the
+/// instance count outgrows the GPU buffer, the buffer is recreated with the next
+/// power of two capacity that fits them, and fully re-uploaded.
+pub struct InstanceBuffer {
+    label: String,
+    instances: Vec<InstanceRaw>,
+    buffer: wgpu::Buffer,
+    capacity: usize,
+    dirty: Option<Range<usize>>,
+}
+
+impl InstanceBuffer {
+    const MIN_CAPACITY: usize = 16;
+
+    pub fn new(device: &wgpu::Device, label: &str, instances: &[Instance]) -> Self {
+        let instances = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        let capacity = instances.len().max(Self::MIN_CAPACITY);
+        let buffer = Self::create_buffer(device, label, capacity);
+
+        Self {
+            label: label.to_string(),
+            dirty: (!instances.is_empty()).then_some(0..instances.len()),
+            instances,
+            buffer,
+            capacity,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, label: &str, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        self.dirty = match self.dirty.take() {
+            Some(dirty) => Some(dirty.start.min(range.start)..dirty.end.max(range.end)),
+            None => Some(range),
+        };
+    }
+
+    /// Range of instances to pass to instanced draw calls.
+    pub fn instance_range(&self) -> Range<u32> {
+        0..self.instances.len() as u32
+    }
+
+    /// Replaces all instances with the given ones. Only the instances that actually
+    /// changed are marked as dirty, so static instances are not re-uploaded.
+    pub fn sync_raw<I: IntoIterator<Item = InstanceRaw>>(&mut self, instances: I) {
+        let mut count = 0;
+        for (index, raw) in instances.into_iter().enumerate() {
+            count = index + 1;
+
+            if let Some(existing) = self.instances.get(index)
+                && bytemuck::bytes_of(existing) == bytemuck::bytes_of(&raw)
+            {
+                continue;
+            }
+
+            if index < self.instances.len() {
+                self.instances[index] = raw;
+            } else {
+                self.instances.push(raw);
+            }
+            self.mark_dirty(index..index + 1);
+        }
+
+        self.instances.truncate(count);
+    }
+
+    /// Uploads all dirty instances to the GPU, growing the buffer if needed.
+    pub fn queue_write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.instances.len() > self.capacity {
+            self.capacity = self.instances.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, &self.label, self.capacity);
+            self.dirty = Some(0..self.instances.len());
+        }
+
+        let Some(dirty) = self.dirty.take() else {
+            return;
+        };
+
+        // Removals might have shrunk the instance list below the dirty range
+        let end = dirty.end.min(self.instances.len());
+        if dirty.start >= end {
+            return;
+        }
+
+        let offset = (dirty.start * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress;
+        queue.write_buffer(
+            &self.buffer,
+            offset,
+            bytemuck::cast_slice(&self.instances[dirty.start..end]),
+        );
+    }
+
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        let size = (self.instances.len() * std::mem::size_of::<InstanceRaw>()) as u64;
+        // wgpu does not allow empty buffer slices. Drawing zero instances never
+        // reads from the buffer, so a one byte slice is fine in that case.
+        self.buffer.slice(..size.max(1))
+    }
 }
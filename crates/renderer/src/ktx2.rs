@@ -1,77 +1,337 @@
 use std::io::Read;
 
-use anyhow::Error;
-use ktx2::{Format, SupercompressionScheme};
+use ktx2::{ColorModel, Format, SupercompressionScheme};
 use wgpu::TextureFormat;
 
-pub fn ktx_to_wgpu_format(format: Option<Format>) -> anyhow::Result<TextureFormat> {
+use crate::texture::TextureError;
+
+pub fn ktx_to_wgpu_format(format: Option<Format>) -> Result<TextureFormat, TextureError> {
+    let Some(format) = format else {
+        return Err(TextureError::UnsupportedFormat(
+            "KTX2 texture has no format. Basis Universal textures need to be transcoded first."
+                .to_string(),
+        ));
+    };
+
+    if let Some(format) = compressed_ktx_to_wgpu_format(format) {
+        return Ok(format);
+    }
+
     match format {
-        Some(Format::E5B9G9R9_UFLOAT_PACK32) => Ok(TextureFormat::Rgb9e5Ufloat),
-        Some(Format::R16G16B16A16_SFLOAT) => Ok(TextureFormat::Rgba16Float),
-        Some(Format::R8G8B8A8_SRGB) => Ok(TextureFormat::Rgba8UnormSrgb),
-        Some(Format::R8G8B8A8_UNORM) => Ok(TextureFormat::Rgba8Unorm),
-        Some(Format::R8G8_UNORM) => Ok(TextureFormat::Rg8Unorm),
-        _ => Err(Error::msg(format!(
+        Format::E5B9G9R9_UFLOAT_PACK32 => Ok(TextureFormat::Rgb9e5Ufloat),
+        Format::R16G16B16A16_SFLOAT => Ok(TextureFormat::Rgba16Float),
+        Format::R8G8B8A8_SRGB => Ok(TextureFormat::Rgba8UnormSrgb),
+        Format::R8G8B8A8_UNORM => Ok(TextureFormat::Rgba8Unorm),
+        Format::R8G8_UNORM => Ok(TextureFormat::Rg8Unorm),
+        Format::R16G16_SFLOAT => Ok(TextureFormat::Rg16Float),
+        _ => Err(TextureError::UnsupportedFormat(format!(
             "Unsupported KTX2 format: {format:?}. Cannot convert it to a wgpu texture format."
         ))),
     }
 }
 
+/// Maps the BC, ETC2/EAC and ASTC formats. Using them requires the matching
+/// `TEXTURE_COMPRESSION_*` feature on the device.
+fn compressed_ktx_to_wgpu_format(format: Format) -> Option<TextureFormat> {
+    let format = match format {
+        // BC1 has no separate RGB variant in wgpu, the alpha is just always 1
+        Format::BC1_RGB_UNORM_BLOCK | Format::BC1_RGBA_UNORM_BLOCK => TextureFormat::Bc1RgbaUnorm,
+        Format::BC1_RGB_SRGB_BLOCK | Format::BC1_RGBA_SRGB_BLOCK => TextureFormat::Bc1RgbaUnormSrgb,
+        Format::BC2_UNORM_BLOCK => TextureFormat::Bc2RgbaUnorm,
+        Format::BC2_SRGB_BLOCK => TextureFormat::Bc2RgbaUnormSrgb,
+        Format::BC3_UNORM_BLOCK => TextureFormat::Bc3RgbaUnorm,
+        Format::BC3_SRGB_BLOCK => TextureFormat::Bc3RgbaUnormSrgb,
+        Format::BC4_UNORM_BLOCK => TextureFormat::Bc4RUnorm,
+        Format::BC4_SNORM_BLOCK => TextureFormat::Bc4RSnorm,
+        Format::BC5_UNORM_BLOCK => TextureFormat::Bc5RgUnorm,
+        Format::BC5_SNORM_BLOCK => TextureFormat::Bc5RgSnorm,
+        Format::BC6H_UFLOAT_BLOCK => TextureFormat::Bc6hRgbUfloat,
+        Format::BC6H_SFLOAT_BLOCK => TextureFormat::Bc6hRgbFloat,
+        Format::BC7_UNORM_BLOCK => TextureFormat::Bc7RgbaUnorm,
+        Format::BC7_SRGB_BLOCK => TextureFormat::Bc7RgbaUnormSrgb,
+        Format::ETC2_R8G8B8_UNORM_BLOCK => TextureFormat::Etc2Rgb8Unorm,
+        Format::ETC2_R8G8B8_SRGB_BLOCK => TextureFormat::Etc2Rgb8UnormSrgb,
+        Format::ETC2_R8G8B8A1_UNORM_BLOCK => TextureFormat::Etc2Rgb8A1Unorm,
+        Format::ETC2_R8G8B8A1_SRGB_BLOCK => TextureFormat::Etc2Rgb8A1UnormSrgb,
+        Format::ETC2_R8G8B8A8_UNORM_BLOCK => TextureFormat::Etc2Rgba8Unorm,
+        Format::ETC2_R8G8B8A8_SRGB_BLOCK => TextureFormat::Etc2Rgba8UnormSrgb,
+        Format::EAC_R11_UNORM_BLOCK => TextureFormat::EacR11Unorm,
+        Format::EAC_R11_SNORM_BLOCK => TextureFormat::EacR11Snorm,
+        Format::EAC_R11G11_UNORM_BLOCK => TextureFormat::EacRg11Unorm,
+        Format::EAC_R11G11_SNORM_BLOCK => TextureFormat::EacRg11Snorm,
+        _ => {
+            let (block, channel) = astc_block_and_channel(format)?;
+            TextureFormat::Astc { block, channel }
+        }
+    };
+
+    Some(format)
+}
+
+fn astc_block_and_channel(format: Format) -> Option<(wgpu::AstcBlock, wgpu::AstcChannel)> {
+    use wgpu::{AstcBlock as B, AstcChannel as C};
+
+    let result = match format {
+        Format::ASTC_4x4_UNORM_BLOCK => (B::B4x4, C::Unorm),
+        Format::ASTC_4x4_SRGB_BLOCK => (B::B4x4, C::UnormSrgb),
+        Format::ASTC_4x4_SFLOAT_BLOCK => (B::B4x4, C::Hdr),
+        Format::ASTC_5x4_UNORM_BLOCK => (B::B5x4, C::Unorm),
+        Format::ASTC_5x4_SRGB_BLOCK => (B::B5x4, C::UnormSrgb),
+        Format::ASTC_5x4_SFLOAT_BLOCK => (B::B5x4, C::Hdr),
+        Format::ASTC_5x5_UNORM_BLOCK => (B::B5x5, C::Unorm),
+        Format::ASTC_5x5_SRGB_BLOCK => (B::B5x5, C::UnormSrgb),
+        Format::ASTC_5x5_SFLOAT_BLOCK => (B::B5x5, C::Hdr),
+        Format::ASTC_6x5_UNORM_BLOCK => (B::B6x5, C::Unorm),
+        Format::ASTC_6x5_SRGB_BLOCK => (B::B6x5, C::UnormSrgb),
+        Format::ASTC_6x5_SFLOAT_BLOCK => (B::B6x5, C::Hdr),
+        Format::ASTC_6x6_UNORM_BLOCK => (B::B6x6, C::Unorm),
+        Format::ASTC_6x6_SRGB_BLOCK => (B::B6x6, C::UnormSrgb),
+        Format::ASTC_6x6_SFLOAT_BLOCK => (B::B6x6, C::Hdr),
+        Format::ASTC_8x5_UNORM_BLOCK => (B::B8x5, C::Unorm),
+        Format::ASTC_8x5_SRGB_BLOCK => (B::B8x5, C::UnormSrgb),
+        Format::ASTC_8x5_SFLOAT_BLOCK => (B::B8x5, C::Hdr),
+        Format::ASTC_8x6_UNORM_BLOCK => (B::B8x6, C::Unorm),
+        Format::ASTC_8x6_SRGB_BLOCK => (B::B8x6, C::UnormSrgb),
+        Format::ASTC_8x6_SFLOAT_BLOCK => (B::B8x6, C::Hdr),
+        Format::ASTC_8x8_UNORM_BLOCK => (B::B8x8, C::Unorm),
+        Format::ASTC_8x8_SRGB_BLOCK => (B::B8x8, C::UnormSrgb),
+        Format::ASTC_8x8_SFLOAT_BLOCK => (B::B8x8, C::Hdr),
+        Format::ASTC_10x5_UNORM_BLOCK => (B::B10x5, C::Unorm),
+        Format::ASTC_10x5_SRGB_BLOCK => (B::B10x5, C::UnormSrgb),
+        Format::ASTC_10x5_SFLOAT_BLOCK => (B::B10x5, C::Hdr),
+        Format::ASTC_10x6_UNORM_BLOCK => (B::B10x6, C::Unorm),
+        Format::ASTC_10x6_SRGB_BLOCK => (B::B10x6, C::UnormSrgb),
+        Format::ASTC_10x6_SFLOAT_BLOCK => (B::B10x6, C::Hdr),
+        Format::ASTC_10x8_UNORM_BLOCK => (B::B10x8, C::Unorm),
+        Format::ASTC_10x8_SRGB_BLOCK => (B::B10x8, C::UnormSrgb),
+        Format::ASTC_10x8_SFLOAT_BLOCK => (B::B10x8, C::Hdr),
+        Format::ASTC_10x10_UNORM_BLOCK => (B::B10x10, C::Unorm),
+        Format::ASTC_10x10_SRGB_BLOCK => (B::B10x10, C::UnormSrgb),
+        Format::ASTC_10x10_SFLOAT_BLOCK => (B::B10x10, C::Hdr),
+        Format::ASTC_12x10_UNORM_BLOCK => (B::B12x10, C::Unorm),
+        Format::ASTC_12x10_SRGB_BLOCK => (B::B12x10, C::UnormSrgb),
+        Format::ASTC_12x10_SFLOAT_BLOCK => (B::B12x10, C::Hdr),
+        Format::ASTC_12x12_UNORM_BLOCK => (B::B12x12, C::Unorm),
+        Format::ASTC_12x12_SRGB_BLOCK => (B::B12x12, C::UnormSrgb),
+        Format::ASTC_12x12_SFLOAT_BLOCK => (B::B12x12, C::Hdr),
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// The two kinds of Basis Universal payloads a KTX2 file can contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BasisUniversal {
+    /// Low quality and very small, always BasisLZ supercompressed
+    Etc1s,
+    /// High quality, usually Zstandard supercompressed
+    Uastc,
+}
+
+/// Detects Basis Universal textures, which have no Vulkan format and describe
+/// their payload in the data format descriptor instead.
+pub fn basis_universal_kind(reader: &ktx2::Reader<&[u8]>) -> Option<BasisUniversal> {
+    let header = reader.header();
+    if header.format.is_some() {
+        return None;
+    }
+    if header.supercompression_scheme == Some(SupercompressionScheme::BasisLZ) {
+        return Some(BasisUniversal::Etc1s);
+    }
+
+    reader.dfd_blocks().find_map(|block| {
+        let basic = ktx2::DfdBlockBasic::parse(block.data).ok()?;
+        match basic.header.color_model? {
+            ColorModel::ETC1S => Some(BasisUniversal::Etc1s),
+            ColorModel::UASTC => Some(BasisUniversal::Uastc),
+            _ => None,
+        }
+    })
+}
+
+/// Device features for all compressed texture formats that the adapter supports.
+pub fn texture_compression_features(adapter: &wgpu::Adapter) -> wgpu::Features {
+    adapter.features()
+        & (wgpu::Features::TEXTURE_COMPRESSION_BC
+            | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+            | wgpu::Features::TEXTURE_COMPRESSION_ASTC
+            | wgpu::Features::TEXTURE_COMPRESSION_ASTC_HDR)
+}
+
+/// Size and dimensions of the texture described by a KTX2 header. The layers
+/// of array textures and the faces of cubes both end up in
+/// `depth_or_array_layers`, in the same order as the KTX2 level data.
 pub fn size_and_dims_from_header(
     header: ktx2::Header,
-) -> (
-    wgpu::Extent3d,
-    wgpu::TextureDimension,
-    wgpu::TextureViewDimension,
-) {
-    let is_cube = header.face_count == 6;
-
-    let layer_count = if is_cube {
-        header.face_count
-    } else {
-        header.pixel_depth.max(1)
+) -> Result<
+    (
+        wgpu::Extent3d,
+        wgpu::TextureDimension,
+        wgpu::TextureViewDimension,
+    ),
+    TextureError,
+> {
+    let is_cube = match header.face_count {
+        1 => false,
+        6 => true,
+        count => {
+            return Err(TextureError::InvalidContainer(format!(
+                "KTX2 texture has {count} faces, but only 1 or 6 are valid."
+            )));
+        }
     };
+    // A layer count of 0 means that this is not an array texture
+    let is_array = header.layer_count > 0;
+    let layer_count = header.layer_count.max(1);
 
-    let size = wgpu::Extent3d {
-        width: header.pixel_width,
-        height: header.pixel_height.max(1),
-        depth_or_array_layers: layer_count,
-    };
+    if header.pixel_width == 0 {
+        return Err(TextureError::InvalidContainer(
+            "KTX2 texture has a width of 0.".to_string(),
+        ));
+    }
 
-    let (dimension, view_dimension) = if header.pixel_height == 0 {
-        (wgpu::TextureDimension::D1, wgpu::TextureViewDimension::D1)
+    let result = if header.pixel_height == 0 {
+        if is_array || is_cube || header.pixel_depth > 0 {
+            return Err(TextureError::InvalidContainer(
+                "1D KTX2 textures can't be arrays, cubes or have a depth.".to_string(),
+            ));
+        }
+        (
+            wgpu::Extent3d {
+                width: header.pixel_width,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            wgpu::TextureDimension::D1,
+            wgpu::TextureViewDimension::D1,
+        )
+    } else if header.pixel_depth > 0 {
+        if is_array || is_cube {
+            return Err(TextureError::InvalidContainer(
+                "3D KTX2 textures can't be arrays or cubes.".to_string(),
+            ));
+        }
+        (
+            wgpu::Extent3d {
+                width: header.pixel_width,
+                height: header.pixel_height,
+                depth_or_array_layers: header.pixel_depth,
+            },
+            wgpu::TextureDimension::D3,
+            wgpu::TextureViewDimension::D3,
+        )
     } else if is_cube {
-        (wgpu::TextureDimension::D2, wgpu::TextureViewDimension::Cube)
-    } else if header.pixel_depth == 0 {
-        (wgpu::TextureDimension::D2, wgpu::TextureViewDimension::D2)
+        if header.pixel_width != header.pixel_height {
+            return Err(TextureError::InvalidContainer(format!(
+                "KTX2 cube faces need to be square, but they are {}x{}.",
+                header.pixel_width, header.pixel_height
+            )));
+        }
+        let view_dimension = if is_array {
+            wgpu::TextureViewDimension::CubeArray
+        } else {
+            wgpu::TextureViewDimension::Cube
+        };
+        (
+            wgpu::Extent3d {
+                width: header.pixel_width,
+                height: header.pixel_height,
+                depth_or_array_layers: layer_count * 6,
+            },
+            wgpu::TextureDimension::D2,
+            view_dimension,
+        )
     } else {
-        (wgpu::TextureDimension::D3, wgpu::TextureViewDimension::D3)
+        let view_dimension = if is_array {
+            wgpu::TextureViewDimension::D2Array
+        } else {
+            wgpu::TextureViewDimension::D2
+        };
+        (
+            wgpu::Extent3d {
+                width: header.pixel_width,
+                height: header.pixel_height,
+                depth_or_array_layers: layer_count,
+            },
+            wgpu::TextureDimension::D2,
+            view_dimension,
+        )
     };
-    (size, dimension, view_dimension)
+
+    Ok(result)
 }
 
-pub fn get_raw_level_data(reader: &ktx2::Reader<&[u8]>) -> anyhow::Result<Vec<Vec<u8>>> {
-    let header = reader.header();
-    let mut levels = Vec::new();
-    if let Some(supercompression_scheme) = header.supercompression_scheme {
-        for level in reader.levels() {
-            match supercompression_scheme {
-                SupercompressionScheme::Zstandard => {
-                    let mut cursor = std::io::Cursor::new(level.data);
-                    let mut decoder = ruzstd::decoding::StreamingDecoder::new(&mut cursor)?;
-                    let mut decompressed = Vec::new();
-                    decoder.read_to_end(&mut decompressed)?;
-                    levels.push(decompressed);
-                }
-                _ => {
-                    return Err(Error::msg(format!(
-                        "Unsupported supercompression scheme: {supercompression_scheme:?}. Only zstd is supported.",
-                    )));
-                }
-            }
+/// Checks the texture against the limits of the device, so that a texture that
+/// is too big fails to load instead of causing a validation error.
+pub fn validate_texture_size(
+    limits: &wgpu::Limits,
+    size: wgpu::Extent3d,
+    dimension: wgpu::TextureDimension,
+    level_count: u32,
+) -> Result<(), TextureError> {
+    let (max_dimension, max_layers) = match dimension {
+        wgpu::TextureDimension::D1 => (limits.max_texture_dimension_1d, 1),
+        wgpu::TextureDimension::D2 => (
+            limits.max_texture_dimension_2d,
+            limits.max_texture_array_layers,
+        ),
+        wgpu::TextureDimension::D3 => (
+            limits.max_texture_dimension_3d,
+            limits.max_texture_dimension_3d,
+        ),
+    };
+
+    if size.width > max_dimension || size.height > max_dimension {
+        return Err(TextureError::GpuLimits(format!(
+            "Texture is {}x{}, but the device only supports up to {max_dimension}.",
+            size.width, size.height
+        )));
+    }
+    if size.depth_or_array_layers > max_layers {
+        return Err(TextureError::GpuLimits(format!(
+            "Texture has {} layers (or a depth of that), but the device only supports up to {max_layers}.",
+            size.depth_or_array_layers
+        )));
+    }
+    if level_count > size.max_mips(dimension) {
+        return Err(TextureError::GpuLimits(format!(
+            "Texture has {level_count} mip levels, but a {dimension:?} texture of this size can have at most {}.",
+            size.max_mips(dimension)
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn get_raw_level_data(reader: &ktx2::Reader<&[u8]>) -> Result<Vec<Vec<u8>>, TextureError> {
+    let supercompression_scheme = reader.header().supercompression_scheme;
+    reader
+        .levels()
+        .map(|level| decode_level(supercompression_scheme, level.data))
+        .collect()
+}
+
+/// Undoes the supercompression of a single level. Levels are compressed
+/// independently, so they can be decoded in any order.
+pub fn decode_level(
+    supercompression_scheme: Option<SupercompressionScheme>,
+    data: &[u8],
+) -> Result<Vec<u8>, TextureError> {
+    match supercompression_scheme {
+        None => Ok(data.to_vec()),
+        Some(SupercompressionScheme::Zstandard) => {
+            let mut cursor = std::io::Cursor::new(data);
+            let mut decoder = ruzstd::decoding::StreamingDecoder::new(&mut cursor)
+                .map_err(|err| TextureError::Supercompression(err.to_string()))?;
+            let mut decompressed = Vec::new();
+            decoder
+                .read_to_end(&mut decompressed)
+                .map_err(|err| TextureError::Supercompression(err.to_string()))?;
+            Ok(decompressed)
         }
-    } else {
-        levels = reader.levels().map(|level| level.data.to_vec()).collect();
+        Some(supercompression_scheme) => Err(TextureError::Supercompression(format!(
+            "Unsupported supercompression scheme: {supercompression_scheme:?}. Only zstd is supported.",
+        ))),
     }
-    Ok(levels)
 }
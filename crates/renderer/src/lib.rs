@@ -1,30 +1,62 @@
+mod asset_server;
 mod camera;
+mod cluster;
+mod deferred;
+mod dof;
+mod environment;
 mod hdr;
 mod input_handling;
 mod instance;
 mod ktx2;
 mod light;
 mod material;
+mod mipmaps;
 mod model;
+mod post_process;
+mod procedural_sky;
 mod resources;
+mod sampler_cache;
+mod scene;
+mod screenshot;
+mod sequence;
+mod sh;
 mod sky;
 mod slang_macros;
+mod ssr;
+mod taa;
 mod texture;
+mod texture_streaming;
 mod wgpu_traits;
 
-use camera::{Camera, CameraProperties, OrbitCameraController, Projection};
+use asset_server::AssetServer;
+use camera::{
+    Camera, CameraPreset, CameraProperties, DepthMode, OrbitCameraController, PhysicalCamera,
+    Projection,
+};
 use cgmath::{Deg, prelude::*};
-use hdr::HdrPipeline;
+use cluster::LightClusterPipeline;
+pub use deferred::RenderPath;
+use deferred::{DeferredPipeline, GBUFFER_FORMATS};
+use dof::{DepthOfFieldPipeline, Focus};
+use hdr::{HDR_BUFFER_FORMAT, HdrPipeline};
 use input_handling::Input;
-use instance::{Instance, InstanceRaw};
-use light::{DrawLight, Light, LightProperties};
+use instance::{InstanceBuffer, InstanceRaw};
+use light::{DrawLight, LightIntensity, LightKind, LightManager, LightProperties};
 use model::{DrawModel, Model, Vertex};
-use sky::SkyPipeline;
+use procedural_sky::PreethamSky;
+use scene::{Attachment, NodeId, SceneGraph, Transform};
+use screenshot::ScreenshotCapture;
+pub use screenshot::ScreenshotFormats;
+pub use sequence::{CameraAnimation, CameraKeyframe, CameraPath, Sequence, Turntable, list_models};
+use sky::{EnvironmentSource, SkyPipeline};
+use ssr::SsrPipeline;
 use std::{cmp, sync::Arc};
+use taa::{TaaPipeline, VELOCITY_FORMAT};
 use texture::FallbackTextures;
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
-use wgpu::{TextureFormat, util::DeviceExt};
+use web_time::Instant;
+use wgpu::TextureFormat;
 use wgpu_traits::AsBindGroup;
 use winit::{
     application::ApplicationHandler,
@@ -35,41 +67,80 @@ use winit::{
     window::Window,
 };
 
+/// Model that is shown when running interactively
+const DEFAULT_MODEL: &str = "debug-roughness-spheres.obj";
+/// Reverse-Z with an infinite far plane avoids z-fighting and clipping in large
+/// scenes. Switch to `DepthMode::Standard` to compare against a regular depth buffer.
+const DEPTH_MODE: DepthMode = DepthMode::ReverseInfinite;
+/// Render targets of the lit pass, which every pipeline drawing into it needs
+const LIT_PASS_FORMATS: [wgpu::TextureFormat; 4] = [
+    HDR_BUFFER_FORMAT,
+    VELOCITY_FORMAT,
+    ssr::NORMAL_ROUGHNESS_FORMAT,
+    ssr::SPECULAR_WEIGHT_FORMAT,
+];
+/// Degrees per second the light pivot turns by, one degree per frame at 60 fps
+const LIGHT_PIVOT_SPEED: f32 = 60.0;
+/// Degrees per second the instances spin and squash by, half a degree per
+/// frame at 60 fps
+const INSTANCE_SPIN_SPEED: f32 = 30.0;
+
 pub struct State {
     hdr_pipeline: HdrPipeline,
     sky_pipeline: SkyPipeline,
+    deferred_pipeline: DeferredPipeline,
+    ssr_pipeline: SsrPipeline,
+    taa_pipeline: TaaPipeline,
+    dof_pipeline: DepthOfFieldPipeline,
+    render_path: RenderPath,
     camera: Camera,
     camera_controller: OrbitCameraController,
+    /// Views saved while running, that can be cycled through
+    camera_bookmarks: Vec<CameraPreset>,
+    next_camera_bookmark: usize,
     clear_color: wgpu::Color,
     config: wgpu::SurfaceConfiguration,
     device: wgpu::Device,
-    instances: Vec<Instance>,
-    instance_buffer: wgpu::Buffer,
+    scene: SceneGraph,
+    model_nodes: Vec<NodeId>,
+    light_pivot_node: NodeId,
+    camera_node: NodeId,
+    instance_buffer: InstanceBuffer,
+    animate_instances: bool,
+    /// Angle the instances have spun by, in radians
+    instance_phase: f32,
+    /// When the last frame was updated, to advance the animations by the time
+    /// it took
+    last_update: Option<Instant>,
+    /// Shows how many lights are evaluated in every cluster
+    show_light_clusters: bool,
     is_surface_configured: bool,
     queue: wgpu::Queue,
     lit_render_pipeline: wgpu::RenderPipeline,
+    /// Draws the meshes into the G-buffer of the deferred path
+    gbuffer_render_pipeline: wgpu::RenderPipeline,
     light_debug_render_pipeline: wgpu::RenderPipeline,
-    surface: wgpu::Surface<'static>,
-    window: Arc<Window>,
+    /// Not there for headless states
+    surface: Option<wgpu::Surface<'static>>,
+    window: Option<Arc<Window>>,
     input: Input,
     depth_texture: texture::Texture,
-    obj_model: Model,
-    light: Light,
+    obj_model: Arc<Model>,
+    light_manager: LightManager,
+    light_cluster_pipeline: LightClusterPipeline,
+    asset_server: AssetServer,
+    screenshots: ScreenshotCapture,
+    /// Settings of the procedural sky, kept while it is turned off
+    procedural_sky: PreethamSky,
+    /// Index of the directional light driven by the sun of the procedural sky
+    sun_light: Option<usize>,
 }
 
 impl State {
     pub async fn new(window: Arc<Window>) -> anyhow::Result<Self> {
         let size = window.inner_size();
 
-        // The Instance is used to create the Surfaces and the Adapters
-        // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
-        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
-            #[cfg(not(target_arch = "wasm32"))]
-            backends: wgpu::Backends::VULKAN,
-            #[cfg(target_arch = "wasm32")]
-            backends: wgpu::Backends::GL,
-            ..Default::default()
-        });
+        let instance = create_instance();
 
         // The Surface represents the part of the window that we will
         // draw on. It needs to be specified when requesting an Adapter
@@ -99,21 +170,7 @@ impl State {
         //    would work on ALL hardware. This typically mean software
         //    rendering instead of relying on hardware.
 
-        let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor {
-                label: None,
-                required_features: wgpu::Features::empty(),
-                // Not all features of WGPU are supported in WebGL
-                // so we need to disable some for that target arch
-                required_limits: if cfg!(target_arch = "wasm32") {
-                    wgpu::Limits::downlevel_webgl2_defaults()
-                } else {
-                    wgpu::Limits::downlevel_defaults()
-                },
-                memory_hints: Default::default(),
-                trace: wgpu::Trace::Off,
-            })
-            .await?;
+        let (device, queue) = request_device(&adapter).await?;
 
         let surface_caps = surface.get_capabilities(&adapter);
 
@@ -131,8 +188,10 @@ impl State {
         // create its SurfaceTextures.
         // The RENDER_ATTACHMENT Usage specifies that the SurfaceTexture
         // will be used to write to the screen.
+        // Copying from the surface is needed for screenshots, where it's supported
         let surface_config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | (surface_caps.usages & wgpu::TextureUsages::COPY_SRC),
             format: surface_format,
             width: size.width,
             height: size.height,
@@ -142,11 +201,64 @@ impl State {
             desired_maximum_frame_latency: 2,
         };
 
+        let mut state = Self::with_device(device, queue, surface_config, DEFAULT_MODEL).await?;
+        state.window = Some(window);
+        state.surface = Some(surface);
+
+        Ok(state)
+    }
+
+    /// Creates a state without a window, that renders `model` into textures of
+    /// the given size. This is used to render image sequences, and works on
+    /// machines without a display.
+    pub async fn new_headless(width: u32, height: u32, model: &str) -> anyhow::Result<Self> {
+        let instance = create_instance();
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await?;
+        let (device, queue) = request_device(&adapter).await?;
+
+        // There is no surface, but its configuration still describes the size
+        // and format of the frames, which are what the surface would prefer
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: TextureFormat::Rgba8Unorm,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::AutoVsync,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+            desired_maximum_frame_latency: 2,
+        };
+
+        let mut state = Self::with_device(device, queue, config, model).await?;
+        state.resize(width, height);
+
+        Ok(state)
+    }
+
+    /// Sets up everything that is rendered, once there is a device.
+    async fn with_device(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        surface_config: wgpu::SurfaceConfiguration,
+        model: &str,
+    ) -> anyhow::Result<Self> {
+        let asset_server = AssetServer::new();
+
         // We initialize the Depth Buffer here but it will get recreated everytime
         // the window is resized. The dimensions of the Depth Buffer has to
         // match the dimensions of the render.
-        let depth_texture =
-            texture::Texture::create_depth_texture(&device, &surface_config, "Depth Texture");
+        let depth_texture = texture::Texture::create_depth_texture(
+            &device,
+            asset_server.sampler_cache(),
+            &surface_config,
+            "Depth Texture",
+        );
 
         let projection = Projection::new(
             surface_config.width,
@@ -163,58 +275,135 @@ impl State {
             yaw: Deg(0.0).into(),
             pitch: Deg(0.0).into(),
             projection,
+            depth_mode: DEPTH_MODE,
+            physical_camera: PhysicalCamera::default(),
+            jitter: cgmath::Vector2::zero(),
         };
 
         let camera = Camera::new(camera_props, &device);
 
-        let light = Light::new(
-            LightProperties {
-                position: [2.0, 2.0, 2.0].into(),
+        // The light positions get overwritten by the scene graph every frame
+        let light_manager = LightManager::new(
+            vec![LightProperties {
                 color: [1.0, 1.0, 1.0],
-                intensity: 1.0,
-            },
+                intensity: LightIntensity::Lumens(800.0),
+                ..Default::default()
+            }],
+            [surface_config.width as f32, surface_config.height as f32],
             &device,
+            asset_server.sampler_cache(),
         );
+        let light_cluster_pipeline =
+            LightClusterPipeline::new(&device, camera.bind_group_layout(), &light_manager);
 
-        let fallback_textures = FallbackTextures::new(&device, &queue).await;
+        let fallback_textures = FallbackTextures::new(&device, &queue, &asset_server).await?;
+
+        let obj_model = asset_server
+            .load_model(model, &device, &queue, &fallback_textures)
+            .await?;
 
-        let obj_model = resources::load_model(
-            "debug-roughness-spheres.obj",
+        let hdr_pipeline = HdrPipeline::new(
+            &device,
             &queue,
+            asset_server.sampler_cache(),
+            &surface_config,
+        )
+        .await?;
+
+        // let sky_source = EnvironmentSource::Baked("rogland_clear_night_cube.ktx2");
+        // let sky_source = EnvironmentSource::Baked("monkstown_castle.ktx2");
+        let sky_source = EnvironmentSource::Baked("large-corridor.ktx2");
+        // let sky_source = EnvironmentSource::Baked("debug-sky-faces.ktx2");
+        // let sky_source = EnvironmentSource::Baked("debug-sky-green-dot.ktx2");
+        // Any equirectangular HDRI in res/ can also be used directly (native only):
+        // let sky_source = EnvironmentSource::Equirectangular("my-hdri.exr");
+        let sky_pipeline = SkyPipeline::new(
             &device,
-            &fallback_textures,
+            &queue,
+            asset_server.sampler_cache(),
+            camera.bind_group_layout(),
+            DEPTH_MODE,
+            sky_source,
         )
-        .await
-        .unwrap();
+        .await?;
 
-        let hdr_pipeline = HdrPipeline::new(&device, &queue, &surface_config).await;
+        let deferred_pipeline = DeferredPipeline::new(
+            &device,
+            asset_server.sampler_cache(),
+            camera.bind_group_layout(),
+            &light_manager,
+            sky_pipeline.bind_group_layout(),
+            &depth_texture.view,
+            surface_config.width,
+            surface_config.height,
+        );
 
-        let sky_pipeline = SkyPipeline::new(&device, &queue, camera.bind_group_layout()).await;
+        let ssr_pipeline = SsrPipeline::new(
+            &device,
+            asset_server.sampler_cache(),
+            camera.bind_group_layout(),
+            sky_pipeline.bind_group_layout(),
+            hdr_pipeline.texture_view(),
+            &depth_texture.view,
+            surface_config.width,
+            surface_config.height,
+        );
+
+        let taa_pipeline = TaaPipeline::new(
+            &device,
+            asset_server.sampler_cache(),
+            hdr_pipeline.texture_view(),
+            &depth_texture.view,
+            surface_config.width,
+            surface_config.height,
+        );
+
+        let dof_pipeline = DepthOfFieldPipeline::new(
+            &device,
+            asset_server.sampler_cache(),
+            camera.bind_group_layout(),
+            hdr_pipeline.texture_view(),
+            &depth_texture.view,
+            surface_config.width,
+            surface_config.height,
+        );
 
-        let lit_render_pipeline = {
-            // create our Shader Module using the .wgsl file
-            let shader_module_desc = wgpu_include_slang_shader!("lit");
+        asset_server.log_stats();
 
+        // Both paths use the lit shader, the deferred one with its G-buffer entry point
+        let (lit_render_pipeline, gbuffer_render_pipeline) = {
             let render_pipeline_layout =
                 device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: Some("Render Pipeline Layout"),
                     bind_group_layouts: &[
                         obj_model.materials[0].bind_group_layout(),
                         camera.bind_group_layout(),
-                        light.bind_group_layout(),
+                        light_manager.bind_group_layout(),
                         sky_pipeline.bind_group_layout(),
                     ],
                     push_constant_ranges: &[],
                 });
 
-            create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                hdr_pipeline.texture_format(),
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc(), InstanceRaw::desc()],
-                wgpu::PrimitiveTopology::TriangleList,
-                shader_module_desc,
+            let create_pipeline = |color_formats: &[wgpu::TextureFormat], fragment_entry| {
+                create_render_pipeline(
+                    &device,
+                    &render_pipeline_layout,
+                    color_formats,
+                    Some(DEPTH_MODE.depth_stencil_state()),
+                    &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                    wgpu::PrimitiveTopology::TriangleList,
+                    // create our Shader Module using the .wgsl file
+                    wgpu_include_slang_shader!("lit"),
+                    fragment_entry,
+                )
+            };
+
+            (
+                create_pipeline(
+                    &LIT_PASS_FORMATS,
+                    light_manager.cluster_masks().shading_entry_point(),
+                ),
+                create_pipeline(&GBUFFER_FORMATS, "fs_gbuffer"),
             )
         };
 
@@ -223,25 +412,31 @@ impl State {
 
             let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Light Pipeline Layout"),
-                bind_group_layouts: &[camera.bind_group_layout(), light.bind_group_layout()],
+                bind_group_layouts: &[
+                    camera.bind_group_layout(),
+                    light_manager.bind_group_layout(),
+                ],
                 push_constant_ranges: &[],
             });
 
             create_render_pipeline(
                 &device,
                 &layout,
-                hdr_pipeline.texture_format(),
-                Some(texture::Texture::DEPTH_FORMAT),
+                &LIT_PASS_FORMATS,
+                Some(DEPTH_MODE.depth_stencil_state()),
                 &[],
                 wgpu::PrimitiveTopology::TriangleList,
                 shader_module_desc,
+                "fs_main",
             )
         };
 
+        let mut scene = SceneGraph::new();
+
         const NUM_INSTANCES_PER_ROW: u32 = 1;
         const SPACE_BETWEEN: f32 = 3.0;
 
-        let instances = (0..NUM_INSTANCES_PER_ROW)
+        let model_transforms = (0..NUM_INSTANCES_PER_ROW)
             .flat_map(|z| {
                 (0..NUM_INSTANCES_PER_ROW).map(move |x| {
                     let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
@@ -254,17 +449,47 @@ impl State {
                         cgmath::Quaternion::from_angle_x(cgmath::Deg(x * rotation_factor))
                             * cgmath::Quaternion::from_angle_z(cgmath::Deg(z * rotation_factor));
 
-                    Instance { position, rotation }
+                    Transform {
+                        translation: position,
+                        rotation,
+                        ..Default::default()
+                    }
                 })
             })
             .collect::<Vec<_>>();
 
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instances Buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
+        let model_nodes = model_transforms
+            .into_iter()
+            .enumerate()
+            .map(|(i, transform)| {
+                scene.add_node(
+                    &format!("Model Instance {i}"),
+                    transform,
+                    Some(Attachment::Mesh { model: 0 }),
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // The light orbits around the origin by rotating its parent pivot node
+        let light_pivot_node = scene.add_node("Light Pivot", Transform::default(), None, None);
+        scene.add_node(
+            "Light",
+            Transform::from_translation([2.0, 2.0, 2.0]),
+            Some(Attachment::Light { light: 0 }),
+            Some(light_pivot_node),
+        );
+
+        // Nodes parented to the camera node will follow the camera around
+        let camera_node = scene.add_node(
+            "Camera",
+            Transform::default(),
+            Some(Attachment::Camera),
+            None,
+        );
+
+        // The scene graph fills this buffer with the world transforms of the model nodes
+        let instance_buffer = InstanceBuffer::new(&device, "Instances Buffer", &[]);
 
         let clear_color = wgpu::Color {
             r: 0.2,
@@ -276,23 +501,43 @@ impl State {
         Ok(Self {
             hdr_pipeline,
             sky_pipeline,
-            surface,
+            deferred_pipeline,
+            ssr_pipeline,
+            taa_pipeline,
+            dof_pipeline,
+            render_path: RenderPath::default(),
+            surface: None,
             device,
             queue,
             config: surface_config,
             is_surface_configured: false,
-            window,
+            window: None,
             clear_color,
             lit_render_pipeline,
+            gbuffer_render_pipeline,
             light_debug_render_pipeline,
             camera,
             camera_controller,
+            camera_bookmarks: Vec::new(),
+            next_camera_bookmark: 0,
             input: Input::new(),
-            instances,
+            scene,
+            model_nodes,
+            light_pivot_node,
+            camera_node,
             instance_buffer,
+            animate_instances: false,
+            instance_phase: 0.0,
+            last_update: None,
+            show_light_clusters: false,
             depth_texture,
             obj_model,
-            light,
+            light_manager,
+            light_cluster_pipeline,
+            asset_server,
+            screenshots: ScreenshotCapture::default(),
+            procedural_sky: PreethamSky::default(),
+            sun_light: None,
         })
     }
 
@@ -308,57 +553,199 @@ impl State {
 
         // We need to recreate the Depth Buffer everytime the window is resized
         // because the dimensions of it need to match the render dimensions
-        self.depth_texture =
-            texture::Texture::create_depth_texture(&self.device, &self.config, "Depth Texture");
+        let sampler_cache = self.asset_server.sampler_cache();
+        self.depth_texture = texture::Texture::create_depth_texture(
+            &self.device,
+            sampler_cache,
+            &self.config,
+            "Depth Texture",
+        );
+
+        self.light_manager.screen_size = [self.config.width as f32, self.config.height as f32];
 
         // Resize HDR render pipeline
-        self.hdr_pipeline
-            .resize(&self.device, self.config.width, self.config.height);
+        self.hdr_pipeline.resize(
+            &self.device,
+            sampler_cache,
+            self.config.width,
+            self.config.height,
+        );
+        self.deferred_pipeline.resize(
+            &self.device,
+            sampler_cache,
+            &self.depth_texture.view,
+            self.config.width,
+            self.config.height,
+        );
+        self.ssr_pipeline.resize(
+            &self.device,
+            sampler_cache,
+            self.hdr_pipeline.texture_view(),
+            &self.depth_texture.view,
+            self.config.width,
+            self.config.height,
+        );
+        self.taa_pipeline.resize(
+            &self.device,
+            sampler_cache,
+            self.hdr_pipeline.texture_view(),
+            &self.depth_texture.view,
+            self.config.width,
+            self.config.height,
+        );
+        self.dof_pipeline.resize(
+            &self.device,
+            sampler_cache,
+            self.hdr_pipeline.texture_view(),
+            &self.depth_texture.view,
+            self.config.width,
+            self.config.height,
+        );
 
         // This is where the Surface gets configured.
         // We need the Surface configured before we can do anything.
-        self.surface.configure(&self.device, &self.config);
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
         self.is_surface_configured = true;
     }
 
     pub fn handle_key(&mut self, event_loop: &ActiveEventLoop, code: KeyCode, is_pressed: bool) {
         match (code, is_pressed) {
             (KeyCode::Escape, true) => event_loop.exit(),
-            (KeyCode::BracketLeft, true) => {
-                self.hdr_pipeline.properties.exposure_ev -= 1.0;
-                self.hdr_pipeline.update_binding_resources();
+            (KeyCode::BracketLeft | KeyCode::BracketRight, true) => {
+                // Exposes the image one stop less or more
+                let camera = &mut self.camera.properties.physical_camera;
+                camera.shutter_speed *= if code == KeyCode::BracketRight {
+                    2.0
+                } else {
+                    0.5
+                };
+
+                log::info!(
+                    "Shutter speed: 1/{:.0} s, EV100: {:.1}",
+                    1.0 / camera.shutter_speed,
+                    camera.ev100()
+                );
+            }
+            (KeyCode::Digit9 | KeyCode::Digit0, true) => {
+                // Opens or closes the aperture by one stop, which changes the
+                // depth of field as well as the exposure
+                let camera = &mut self.camera.properties.physical_camera;
+                camera.aperture *= if code == KeyCode::Digit0 {
+                    std::f32::consts::SQRT_2
+                } else {
+                    std::f32::consts::FRAC_1_SQRT_2
+                };
+
+                log::info!(
+                    "Aperture: f/{:.1}, EV100: {:.1}",
+                    camera.aperture,
+                    camera.ev100()
+                );
+            }
+            (KeyCode::KeyM, true) => {
+                self.render_path = match self.render_path {
+                    RenderPath::Forward => RenderPath::Deferred,
+                    RenderPath::Deferred => RenderPath::Forward,
+                };
 
-                log::info!("Exposure EV: {}", self.hdr_pipeline.properties.exposure_ev);
+                log::info!("Render path: {:?}", self.render_path);
             }
-            (KeyCode::BracketRight, true) => {
-                self.hdr_pipeline.properties.exposure_ev += 1.0;
-                self.light.update_binding_resources();
+            (KeyCode::KeyL, true) => {
+                self.ssr_pipeline.properties.enabled = !self.ssr_pipeline.properties.enabled;
 
-                log::info!("Exposure EV: {}", self.hdr_pipeline.properties.exposure_ev);
+                log::info!(
+                    "Screen-space reflections: {}",
+                    self.ssr_pipeline.properties.enabled
+                );
             }
-            (KeyCode::Quote, true) => {
-                self.light.properties.intensity /= 2.0;
-                self.light.update_binding_resources();
+            (KeyCode::KeyT, true) => {
+                self.taa_pipeline.properties.enabled = !self.taa_pipeline.properties.enabled;
+                self.taa_pipeline.reset_history();
 
-                log::info!("Light intensity: {}", self.light.properties.intensity);
+                log::info!("TAA: {}", self.taa_pipeline.properties.enabled);
             }
-            (KeyCode::Backslash, true) => {
-                self.light.properties.intensity *= 2.0;
-                self.light.update_binding_resources();
+            (KeyCode::KeyF, true) => {
+                self.dof_pipeline.properties.enabled = !self.dof_pipeline.properties.enabled;
 
-                log::info!("Light intensity: {}", self.light.properties.intensity);
+                log::info!("Depth of field: {}", self.dof_pipeline.properties.enabled);
+            }
+            (KeyCode::KeyG, true) => {
+                // Manual focus is set to what the camera currently orbits around
+                self.dof_pipeline.properties.focus = match self.dof_pipeline.properties.focus {
+                    Focus::Auto => Focus::Distance(self.camera_controller.orbit_radius()),
+                    Focus::Distance(_) => Focus::Auto,
+                };
+
+                log::info!("Focus: {:?}", self.dof_pipeline.properties.focus);
+            }
+            (KeyCode::Quote, true) => {
+                for light in self.light_manager.lights.iter_mut() {
+                    light.intensity = light.intensity.scaled(0.5);
+                    log::info!("Light intensity: {:?}", light.intensity);
+                }
+            }
+            (KeyCode::Backslash, true) => {
+                for light in self.light_manager.lights.iter_mut() {
+                    light.intensity = light.intensity.scaled(2.0);
+                    log::info!("Light intensity: {:?}", light.intensity);
+                }
             }
             (KeyCode::Minus, true) => {
-                self.sky_pipeline.properties.exposure_ev -= 1.0;
+                self.sky_pipeline.properties.intensity /= 2.0;
                 self.sky_pipeline.queue_write_binding_resources(&self.queue);
 
-                log::info!("Sky Exposure: {}", self.sky_pipeline.properties.exposure_ev);
+                log::info!(
+                    "Sky intensity: {} cd/m²",
+                    self.sky_pipeline.properties.intensity
+                );
             }
             (KeyCode::Equal, true) => {
-                self.sky_pipeline.properties.exposure_ev += 1.0;
+                self.sky_pipeline.properties.intensity *= 2.0;
                 self.sky_pipeline.queue_write_binding_resources(&self.queue);
 
-                log::info!("Sky Exposure: {}", self.sky_pipeline.properties.exposure_ev);
+                log::info!(
+                    "Sky intensity: {} cd/m²",
+                    self.sky_pipeline.properties.intensity
+                );
+            }
+            (KeyCode::KeyI, true) => {
+                // Add a new instance to the right of the last one
+                let count = self.model_nodes.len();
+                let node = self.scene.add_node(
+                    &format!("Model Instance {count}"),
+                    Transform::from_translation([3.0 * count as f32, 0.0, 0.0]),
+                    Some(Attachment::Mesh { model: 0 }),
+                    None,
+                );
+                self.model_nodes.push(node);
+
+                log::info!("Instance count: {}", self.model_nodes.len());
+            }
+            (KeyCode::KeyK, true) => {
+                if self.model_nodes.len() > 1
+                    && let Some(node) = self.model_nodes.pop()
+                {
+                    self.scene.remove_node(node);
+                }
+
+                log::info!("Instance count: {}", self.model_nodes.len());
+            }
+            (KeyCode::KeyJ, true) => {
+                self.add_light_ring();
+
+                log::info!("Light count: {}", self.light_manager.lights.len());
+            }
+            (KeyCode::KeyC, true) => {
+                self.show_light_clusters = !self.show_light_clusters;
+
+                log::info!("Light cluster heatmap: {}", self.show_light_clusters);
+            }
+            (KeyCode::KeyR, true) => {
+                self.animate_instances = !self.animate_instances;
+
+                log::info!("Toggled instance animation");
             }
             (KeyCode::KeyS, true) => {
                 self.sky_pipeline.properties.debug_sh_coefficients =
@@ -367,57 +754,409 @@ impl State {
 
                 log::info!("Toggled sky SH debug view");
             }
+            (KeyCode::Comma, true) => {
+                self.sky_pipeline.properties.rotation -= Deg(15.0);
+                self.sky_pipeline.queue_write_binding_resources(&self.queue);
+
+                log::info!("Sky rotation: {:?}", self.sky_pipeline.properties.rotation);
+            }
+            (KeyCode::Period, true) => {
+                self.sky_pipeline.properties.rotation += Deg(15.0);
+                self.sky_pipeline.queue_write_binding_resources(&self.queue);
+
+                log::info!("Sky rotation: {:?}", self.sky_pipeline.properties.rotation);
+            }
+            (KeyCode::KeyD, true) => {
+                self.sky_pipeline.properties.use_dfg_lut =
+                    !self.sky_pipeline.properties.use_dfg_lut;
+                self.sky_pipeline.queue_write_binding_resources(&self.queue);
+
+                log::info!("Use DFG LUT: {}", self.sky_pipeline.properties.use_dfg_lut);
+            }
+            (KeyCode::Digit1 | KeyCode::Numpad1, true) => {
+                self.animate_camera_to(CameraPreset::front);
+
+                log::info!("Camera: front");
+            }
+            (KeyCode::Digit3 | KeyCode::Numpad3, true) => {
+                self.animate_camera_to(CameraPreset::side);
+
+                log::info!("Camera: side");
+            }
+            (KeyCode::Digit7 | KeyCode::Numpad7, true) => {
+                self.animate_camera_to(CameraPreset::top);
+
+                log::info!("Camera: top");
+            }
+            (KeyCode::Digit5 | KeyCode::Numpad5, true) => {
+                let orthographic = !self.camera_controller.is_orthographic();
+                self.camera_controller.set_orthographic(orthographic);
+                self.taa_pipeline.reset_history();
+
+                log::info!("Orthographic camera: {orthographic}");
+            }
+            (KeyCode::KeyB, true) => {
+                self.camera_bookmarks.push(self.camera_controller.preset());
+
+                log::info!("Saved camera bookmark {}", self.camera_bookmarks.len());
+            }
+            (KeyCode::KeyN, true) => {
+                if let Some(bookmark) = self.camera_bookmarks.get(self.next_camera_bookmark) {
+                    self.camera_controller.animate_to(*bookmark);
+
+                    log::info!("Camera bookmark {}", self.next_camera_bookmark + 1);
+                    self.next_camera_bookmark =
+                        (self.next_camera_bookmark + 1) % self.camera_bookmarks.len();
+                }
+            }
+            (KeyCode::F12, true) => {
+                self.capture_screenshot();
+
+                log::info!("Capturing screenshot");
+            }
+            (KeyCode::KeyP, true) => {
+                let enable = self.sky_pipeline.properties.procedural().is_none();
+                if let Err(err) = self.set_procedural_sky(enable) {
+                    log::error!("Failed to generate the procedural sky: {err}");
+                    return;
+                }
+                // Daylight is orders of magnitude brighter than indoor lighting
+                self.camera.properties.physical_camera = if enable {
+                    PhysicalCamera::SUNNY_16
+                } else {
+                    PhysicalCamera::INDOOR
+                };
+
+                log::info!("Procedural sky: {enable}");
+            }
+            (KeyCode::ArrowUp | KeyCode::ArrowDown, true) => {
+                let step = if code == KeyCode::ArrowUp { 5.0 } else { -5.0 };
+                let elevation = self.procedural_sky.sun_elevation + Deg(step);
+                self.procedural_sky.sun_elevation = Deg(elevation.0.clamp(0.0, 90.0));
+                if let Err(err) =
+                    self.set_procedural_sky(self.sky_pipeline.properties.procedural().is_some())
+                {
+                    log::error!("Failed to generate the procedural sky: {err}");
+                }
+
+                log::info!("Sun elevation: {:?}", self.procedural_sky.sun_elevation);
+            }
+            (KeyCode::ArrowLeft | KeyCode::ArrowRight, true) => {
+                let step = if code == KeyCode::ArrowRight {
+                    15.0
+                } else {
+                    -15.0
+                };
+                self.procedural_sky.sun_azimuth =
+                    (self.procedural_sky.sun_azimuth + Deg(step)).normalize();
+                if let Err(err) =
+                    self.set_procedural_sky(self.sky_pipeline.properties.procedural().is_some())
+                {
+                    log::error!("Failed to generate the procedural sky: {err}");
+                }
+
+                log::info!("Sun azimuth: {:?}", self.procedural_sky.sun_azimuth);
+            }
             _ => {}
         }
     }
 
+    /// Moves the camera to a canonical view of what it currently orbits around.
+    fn animate_camera_to(&mut self, preset: fn(cgmath::Point3<f32>, f32) -> CameraPreset) {
+        let preset = preset(
+            self.camera_controller.target(),
+            self.camera_controller.orbit_radius(),
+        );
+        self.camera_controller.animate_to(preset);
+    }
+
+    /// Saves the next frame as a PNG of what is shown on screen, and an EXR of
+    /// the scene-linear HDR render before tonemapping.
+    pub fn capture_screenshot(&mut self) {
+        self.screenshots.request();
+    }
+
+    /// Turns the procedural sky on or off, regenerating its environment from
+    /// the current settings.
+    fn set_procedural_sky(&mut self, enable: bool) -> anyhow::Result<()> {
+        let sky = enable.then_some(self.procedural_sky);
+        self.sky_pipeline.set_procedural_sky(
+            &self.device,
+            &self.queue,
+            self.asset_server.sampler_cache(),
+            sky,
+        )
+    }
+
+    /// Adds a ring of small colored point lights that orbit with the first
+    /// light, to see how the light culling holds up with many lights. Every
+    /// ring is wider than the one before.
+    fn add_light_ring(&mut self) {
+        use std::f32::consts::TAU;
+        const RING_LIGHT_COUNT: usize = 32;
+
+        let first = self.light_manager.lights.len();
+        let max_lights = self.light_manager.max_lights();
+        if first + RING_LIGHT_COUNT > max_lights {
+            log::warn!("Only {max_lights} lights are supported.");
+            return;
+        }
+
+        let ring_radius = 1.5 + 0.5 * (first / RING_LIGHT_COUNT) as f32;
+        for i in 0..RING_LIGHT_COUNT {
+            let angle = TAU * i as f32 / RING_LIGHT_COUNT as f32;
+            let index = self.light_manager.lights.len();
+            // Cycle through the hues around the ring
+            let hue = |offset: f32| 0.5 + 0.5 * (angle + offset).cos();
+            self.light_manager.lights.push(LightProperties {
+                color: [hue(0.0), hue(-TAU / 3.0), hue(TAU / 3.0)],
+                intensity: LightIntensity::Lumens(100.0),
+                radius: 2.0,
+                ..Default::default()
+            });
+            self.scene.add_node(
+                &format!("Light {index}"),
+                Transform::from_translation([
+                    ring_radius * angle.cos(),
+                    0.25,
+                    ring_radius * angle.sin(),
+                ]),
+                Some(Attachment::Light { light: index }),
+                Some(self.light_pivot_node),
+            );
+        }
+    }
+
+    /// Adds or updates the directional light that follows the sun of the
+    /// procedural sky. The light keeps its slot once added, and is turned off
+    /// instead of removed, so that the indices of the lights added after it
+    /// stay valid.
+    fn update_sun_light(&mut self) {
+        let sky = self
+            .sky_pipeline
+            .properties
+            .procedural()
+            .filter(|sky| sky.drive_sun_light)
+            .copied();
+
+        let Some(sky) = sky else {
+            if let Some(index) = self.sun_light {
+                self.light_manager.lights[index].intensity = LightIntensity::Lux(0.0);
+            }
+            return;
+        };
+
+        let index = match self.sun_light {
+            Some(index) => index,
+            None => {
+                let max_lights = self.light_manager.max_lights();
+                if self.light_manager.lights.len() >= max_lights {
+                    log::warn!("Only {max_lights} lights are supported, the sun has no light.");
+                    return;
+                }
+                self.light_manager.lights.push(LightProperties {
+                    kind: LightKind::Directional,
+                    ..Default::default()
+                });
+                let index = self.light_manager.lights.len() - 1;
+                self.sun_light = Some(index);
+                index
+            }
+        };
+
+        // The sun direction is in sky space, which is rotated along with the sky
+        let rotation = cgmath::Matrix3::from_angle_y(self.sky_pipeline.properties.rotation);
+        let light = &mut self.light_manager.lights[index];
+        light.direction = -(rotation * sky.sun_direction());
+        light.color = sky.sun_color();
+        light.intensity = LightIntensity::Lux(sky.sun_intensity);
+    }
+
     fn update_camera(&mut self) {
-        self.camera_controller
-            .update_camera(&mut self.camera.properties);
         self.camera
             .properties
             .projection
             .resize(self.config.width, self.config.height);
 
         self.camera.queue_write_binding_resources(&self.queue);
+
+        // keep the camera node in sync so that its children follow the camera
+        let camera_node = self.scene.node_mut(self.camera_node);
+        camera_node.local.translation = self.camera.properties.position.to_vec();
+    }
+
+    fn update_light(&mut self, dt: f32) {
+        let transform = cgmath::Quaternion::from_axis_angle(
+            (0.0, 1.0, 0.0).into(),
+            cgmath::Deg(LIGHT_PIVOT_SPEED * dt),
+        );
+
+        // rotate the light pivot around y-axis, along with the light rings
+        let pivot = self.scene.node_mut(self.light_pivot_node);
+        pivot.local.rotation = transform * pivot.local.rotation;
+    }
+
+    fn update_instances(&mut self, dt: f32) {
+        if !self.animate_instances {
+            return;
+        }
+
+        let spin = cgmath::Deg(INSTANCE_SPIN_SPEED * dt);
+        let spin_rotation = cgmath::Quaternion::from_angle_y(spin);
+        self.instance_phase =
+            (self.instance_phase + cgmath::Rad::from(spin).0).rem_euclid(std::f32::consts::TAU);
+        for (index, node) in self.model_nodes.iter().enumerate() {
+            let transform = &mut self.scene.node_mut(*node).local;
+            // Renormalize, so the rotation doesn't drift over many frames
+            transform.rotation = (spin_rotation * transform.rotation).normalize();
+
+            // squash and stretch each instance slightly out of phase with each other
+            let phase = self.instance_phase + index as f32;
+            let stretch = 1.0 + 0.1 * phase.sin();
+            transform.scale = cgmath::Vector3::new(1.0 / stretch, stretch, 1.0 / stretch);
+        }
     }
 
-    fn update_light(&mut self) {
-        let position = self.light.properties.position;
-        let transform =
-            cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0));
+    fn update_scene(&mut self) {
+        self.scene.update_world_transforms();
 
-        // rotate around y-axis by one degree
-        self.light.properties.position = transform * position;
+        self.scene.write_instances(0, &mut self.instance_buffer);
+        self.instance_buffer.queue_write(&self.device, &self.queue);
 
-        // update the light buffer
-        self.light.queue_write_binding_resources(&self.queue);
+        self.scene.write_lights(&mut self.light_manager);
+        // The heatmap replaces the exposed lighting, so it is shown as is
+        self.light_manager.cluster_heatmap = self.show_light_clusters.then_some(1.0);
+        self.light_manager
+            .queue_write_binding_resources(&self.queue);
     }
 
     pub fn update(&mut self) {
         let input = self.input.data();
 
         self.camera_controller.process_input(input);
-        self.update_camera();
-        self.update_light();
+        self.camera_controller.advance_transition();
+        self.camera_controller
+            .update_camera(&mut self.camera.properties);
+
+        let now = Instant::now();
+        // The first frame has nothing to measure against
+        let dt = self
+            .last_update
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+        self.last_update = Some(now);
+        self.update_frame(dt);
+    }
 
-        self.hdr_pipeline.queue_write_binding_resources(&self.queue);
+    /// Advances the animations by `dt` seconds and uploads everything that
+    /// changed. The camera properties need to be set before.
+    fn update_frame(&mut self, dt: f32) {
+        self.camera.properties.jitter = self.taa_pipeline.next_jitter();
+        self.update_camera();
+        self.update_light(dt);
+        self.update_instances(dt);
+        self.update_sun_light();
+        self.update_scene();
+
+        self.screenshots.poll(&self.device);
+        self.sky_pipeline.stream_textures(
+            &self.device,
+            &self.queue,
+            self.asset_server.sampler_cache(),
+        );
+        self.ssr_pipeline.queue_write_binding_resources(&self.queue);
+        self.taa_pipeline
+            .queue_write_binding_resources(&self.queue, self.camera.properties.depth_mode);
+        self.dof_pipeline
+            .queue_write_binding_resources(&self.queue, &self.camera.properties);
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        let (Some(window), Some(surface)) = (&self.window, &self.surface) else {
+            return Ok(());
+        };
+
         // This is where all the magic happens!
-        self.window.request_redraw();
+        window.request_redraw();
 
         if !self.is_surface_configured {
             return Ok(());
         }
 
-        let output = self.surface.get_current_texture()?;
+        let output = surface.get_current_texture()?;
+        self.render_to(&output.texture);
+        output.present();
 
-        // This creates the View 'into' the Surface texture.
+        Ok(())
+    }
+
+    /// G-buffer and lighting passes of the deferred path, which leave the
+    /// same targets behind as drawing the meshes in the lit pass.
+    fn draw_gbuffer(&self, encoder: &mut wgpu::CommandEncoder) {
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT);
+        let [base_color, normal, emissive] = self.deferred_pipeline.gbuffer_views();
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("G-Buffer Render Pass"),
+            color_attachments: &[
+                color_attachment(base_color, clear),
+                color_attachment(normal, clear),
+                color_attachment(emissive, clear),
+                color_attachment(self.taa_pipeline.velocity_view(), clear),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(self.camera.properties.depth_mode.far_depth()),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice());
+        render_pass.set_pipeline(&self.gbuffer_render_pipeline);
+        render_pass.draw_model_instanced(
+            &self.obj_model,
+            self.instance_buffer.instance_range(),
+            self.camera.bind_group(),
+            self.light_manager.bind_group(),
+            self.sky_pipeline.bind_group(),
+        );
+        drop(render_pass);
+
+        // The depth buffer is read here, so it can't be attached
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Deferred Lighting Render Pass"),
+            color_attachments: &[
+                color_attachment(
+                    self.hdr_pipeline.texture_view(),
+                    wgpu::LoadOp::Clear(self.clear_color),
+                ),
+                color_attachment(self.ssr_pipeline.normal_roughness_view(), clear),
+                color_attachment(self.ssr_pipeline.specular_weight_view(), clear),
+            ],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+
+        self.deferred_pipeline.draw_lighting_in_render_pass(
+            &mut render_pass,
+            self.camera.bind_group(),
+            self.light_manager.bind_group(),
+            self.sky_pipeline.bind_group(),
+        );
+    }
+
+    /// Renders a frame into `target`, which needs the size and format of the
+    /// surface configuration.
+    fn render_to(&mut self, target: &wgpu::Texture) {
+        // This creates the View 'into' the target texture.
         // We need this View to control how the render code
         // interacts with the texture.
-        let view = output.texture.create_view(&Default::default());
+        let view = target.create_view(&Default::default());
 
         // the CommandEncoder is the equivalent of the Command Buffer
         // from other graphics frameworks. The Encoder build a buffer
@@ -428,27 +1167,60 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
+        self.light_cluster_pipeline.draw(
+            &mut encoder,
+            self.camera.bind_group(),
+            &self.light_manager,
+        );
+
+        let deferred = self.render_path == RenderPath::Deferred;
+        if deferred {
+            self.draw_gbuffer(&mut encoder);
+        }
+
+        // The deferred path has already drawn the meshes, and the sky and light
+        // debug pipelines draw over them
+        let load = |clear| {
+            if deferred {
+                wgpu::LoadOp::Load
+            } else {
+                wgpu::LoadOp::Clear(clear)
+            }
+        };
+
         // Create a render pass to clear the screen and draw lit meshes
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Lit Render Pass"),
             // note that color_attachments is a "sparse" array.
             // This allows us to have multiple render targets but only
             // provide the ones that we care about.
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            color_attachments: &[
                 // Render into the hdr frame buffer, NOT directly into the surface buffer
                 // We will later call hdr_pipeline.draw() to draw into the surface buffer
-                view: self.hdr_pipeline.texture_view(),
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(self.clear_color),
-                    store: wgpu::StoreOp::Store,
-                },
-                depth_slice: None,
-            })],
+                color_attachment(self.hdr_pipeline.texture_view(), load(self.clear_color)),
+                // Motion vectors for TAA
+                color_attachment(
+                    self.taa_pipeline.velocity_view(),
+                    load(wgpu::Color::TRANSPARENT),
+                ),
+                // Inputs of the screen-space reflections
+                color_attachment(
+                    self.ssr_pipeline.normal_roughness_view(),
+                    load(wgpu::Color::TRANSPARENT),
+                ),
+                color_attachment(
+                    self.ssr_pipeline.specular_weight_view(),
+                    load(wgpu::Color::TRANSPARENT),
+                ),
+            ],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                 view: &self.depth_texture.view,
                 depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
+                    load: if deferred {
+                        wgpu::LoadOp::Load
+                    } else {
+                        wgpu::LoadOp::Clear(self.camera.properties.depth_mode.far_depth())
+                    },
                     store: wgpu::StoreOp::Store,
                 }),
                 stencil_ops: None,
@@ -457,19 +1229,25 @@ impl State {
             timestamp_writes: None,
         });
 
-        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        if !deferred {
+            render_pass.set_vertex_buffer(1, self.instance_buffer.slice());
 
-        render_pass.set_pipeline(&self.lit_render_pipeline);
-        render_pass.draw_model_instanced(
-            &self.obj_model,
-            0..self.instances.len() as u32,
-            self.camera.bind_group(),
-            self.light.bind_group(),
-            self.sky_pipeline.bind_group(),
-        );
+            render_pass.set_pipeline(&self.lit_render_pipeline);
+            render_pass.draw_model_instanced(
+                &self.obj_model,
+                self.instance_buffer.instance_range(),
+                self.camera.bind_group(),
+                self.light_manager.bind_group(),
+                self.sky_pipeline.bind_group(),
+            );
+        }
 
         render_pass.set_pipeline(&self.light_debug_render_pipeline);
-        render_pass.draw_light(self.camera.bind_group(), self.light.bind_group());
+        render_pass.draw_lights(
+            self.light_manager.debug_light_count(),
+            self.camera.bind_group(),
+            self.light_manager.bind_group(),
+        );
 
         self.sky_pipeline
             .draw_in_render_pass(&mut render_pass, self.camera.bind_group());
@@ -479,26 +1257,126 @@ impl State {
         // encoder.finish() down below.
         drop(render_pass);
 
+        if self.ssr_pipeline.properties.enabled {
+            self.ssr_pipeline.draw(
+                &mut encoder,
+                self.camera.bind_group(),
+                self.sky_pipeline.bind_group(),
+                self.hdr_pipeline.texture(),
+            );
+        }
+
+        if self.taa_pipeline.properties.enabled {
+            self.taa_pipeline
+                .draw(&mut encoder, self.hdr_pipeline.texture());
+        }
+
+        if self.dof_pipeline.is_active(&self.camera.properties) {
+            self.dof_pipeline.draw(
+                &mut encoder,
+                self.camera.bind_group(),
+                self.hdr_pipeline.texture(),
+            );
+        }
+
         // Apply tonemapping and transform to output color space
         self.hdr_pipeline.draw_to_surface(&mut encoder, &view);
 
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+        self.screenshots.record(
+            &self.device,
+            &mut encoder,
+            target,
+            self.hdr_pipeline.texture(),
+            self.camera.properties.physical_camera.ev100(),
+        );
 
-        Ok(())
+        self.queue.submit(std::iter::once(encoder.finish()));
+        self.screenshots.submitted();
     }
 }
 
+/// The Instance is used to create the Surfaces and the Adapters
+fn create_instance() -> wgpu::Instance {
+    // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
+    wgpu::Instance::new(&wgpu::InstanceDescriptor {
+        #[cfg(not(target_arch = "wasm32"))]
+        backends: wgpu::Backends::VULKAN,
+        #[cfg(target_arch = "wasm32")]
+        backends: wgpu::Backends::GL,
+        ..Default::default()
+    })
+}
+
+async fn request_device(adapter: &wgpu::Adapter) -> anyhow::Result<(wgpu::Device, wgpu::Queue)> {
+    let device = adapter
+        .request_device(&wgpu::DeviceDescriptor {
+            label: None,
+            // Compressed textures can only be loaded if their feature is enabled
+            required_features: ktx2::texture_compression_features(adapter),
+            // Not all features of WGPU are supported in WebGL
+            // so we need to disable some for that target arch
+            required_limits: if cfg!(target_arch = "wasm32") {
+                wgpu::Limits::downlevel_webgl2_defaults()
+            } else {
+                wgpu::Limits::downlevel_defaults()
+            },
+            memory_hints: Default::default(),
+            trace: wgpu::Trace::Off,
+        })
+        .await?;
+
+    Ok(device)
+}
+
+/// Color attachment that is stored after the pass.
+fn color_attachment(
+    view: &wgpu::TextureView,
+    load: wgpu::LoadOp<wgpu::Color>,
+) -> Option<wgpu::RenderPassColorAttachment<'_>> {
+    Some(wgpu::RenderPassColorAttachment {
+        view,
+        resolve_target: None,
+        ops: wgpu::Operations {
+            load,
+            store: wgpu::StoreOp::Store,
+        },
+        depth_slice: None,
+    })
+}
+
+/// Whether the device can run compute shaders with storage buffers, which
+/// WebGL2 can't.
+pub(crate) fn supports_compute(device: &wgpu::Device) -> bool {
+    let limits = device.limits();
+    limits.max_compute_workgroups_per_dimension > 0
+        && limits.max_storage_buffers_per_shader_stage > 0
+}
+
+#[allow(clippy::too_many_arguments)]
 fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
-    depth_format: Option<wgpu::TextureFormat>,
+    color_formats: &[wgpu::TextureFormat],
+    depth_stencil: Option<wgpu::DepthStencilState>,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     topology: wgpu::PrimitiveTopology,
     shader: wgpu::ShaderModuleDescriptor,
+    fragment_entry: &str,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(shader);
+    let targets = color_formats
+        .iter()
+        .map(|format| {
+            Some(wgpu::ColorTargetState {
+                format: *format,
+                blend: Some(wgpu::BlendState {
+                    alpha: wgpu::BlendComponent::REPLACE,
+                    color: wgpu::BlendComponent::REPLACE,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            })
+        })
+        .collect::<Vec<_>>();
 
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some(&format!("{:?}", shader)),
@@ -511,15 +1389,8 @@ fn create_render_pipeline(
         },
         fragment: Some(wgpu::FragmentState {
             module: &shader,
-            entry_point: Some("fs_main"),
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
+            entry_point: Some(fragment_entry),
+            targets: &targets,
             compilation_options: Default::default(),
         }),
         primitive: wgpu::PrimitiveState {
@@ -534,13 +1405,7 @@ fn create_render_pipeline(
             // Requires Features::CONSERVATIVE_RASTERIZATION
             conservative: false,
         },
-        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
-            format,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::LessEqual,
-            stencil: wgpu::StencilState::default(),
-            bias: wgpu::DepthBiasState::default(),
-        }),
+        depth_stencil,
         multisample: wgpu::MultisampleState {
             count: 1,
             mask: !0,
@@ -585,8 +1450,9 @@ impl App {
             Ok(_) => {}
             Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
                 // reconfigure the Surface if it is lost or outdated
-                let size = state.window.inner_size();
-                state.resize(size.width, size.height);
+                if let Some(size) = state.window.as_ref().map(|window| window.inner_size()) {
+                    state.resize(size.width, size.height);
+                }
             }
             Err(e) => {
                 log::error!("Unable to render {e}");
@@ -658,12 +1524,9 @@ impl ApplicationHandler<State> for App {
     #[allow(unused_mut)]
     fn user_event(&mut self, _event_loop: &ActiveEventLoop, mut event: State) {
         #[cfg(target_arch = "wasm32")]
-        {
-            event.window.request_redraw();
-            event.resize(
-                event.window.inner_size().width,
-                event.window.inner_size().height,
-            );
+        if let Some(window) = event.window.clone() {
+            window.request_redraw();
+            event.resize(window.inner_size().width, window.inner_size().height);
         }
         self.state = Some(event);
     }
@@ -1,86 +1,252 @@
-use crate::wgpu_traits::AsBindGroup;
+use crate::{cluster, sampler_cache::SamplerCache, wgpu_traits::AsBindGroup};
 use wgpu::{BindGroup, BindGroupLayout, Buffer, Queue, util::DeviceExt};
 
+/// Maximum number of lights in the uniform light list, which is all the
+/// fragment binning of WebGL2 has. Every cluster has a bit per light, so this
+/// needs to be a multiple of 128 to fill whole texels.
+/// This needs to match MAX_LIGHTS in light.slang
+pub const MAX_LIGHTS: usize = 256;
+/// Maximum number of lights where they are binned by the compute shader, see
+/// [`cluster::ClusterMasks`]. This needs to be a multiple of 32.
+/// This needs to match MAX_STORAGE_LIGHTS in light.slang
+pub const MAX_STORAGE_LIGHTS: usize = 1024;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct LightUniform {
     pub position: [f32; 3],
+    /// In candela for point lights, and in lux for directional lights
     pub intensity: f32,
     pub color: [f32; 3],
+    pub kind: u32,
+    pub direction: [f32; 3],
     // The alignment of WGSL structs need to be powers of 2.
-    // In this case, our vec3 would have a size of 12 bytes, so we need
-    // to align to the next largest power of two: 16.
-    // An alternative is to just use vec4's even if we need only 3 channels.
-    pub _padding: u32,
+    // In this case, our vec3 would have a size of 12 bytes, so the float
+    // after it fills it up to the next largest power of two: 16.
+    pub inv_radius: f32,
 }
 
 impl From<&LightProperties> for LightUniform {
     fn from(value: &LightProperties) -> Self {
         LightUniform {
             position: value.position.into(),
-            intensity: value.intensity,
+            intensity: value.intensity.to_shader_units(value.kind),
             color: value.color,
-            _padding: 0,
+            kind: value.kind as u32,
+            direction: value.direction.into(),
+            inv_radius: 1.0 / value.radius,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightListUniform {
+    pub lights: [LightUniform; MAX_LIGHTS],
+    /// Number of lights, which is more than `lights` has room for when the
+    /// rest are in the storage buffer of the compute binning
+    pub count: u32,
+    /// Size of the render in pixels, which fragments need to find their cluster
+    pub screen_size: [f32; 2],
+    /// Luminance of the cluster heatmap, which replaces the lighting unless
+    /// it is 0
+    pub heatmap_luminance: f32,
+}
+
+impl From<&[LightProperties]> for LightListUniform {
+    fn from(value: &[LightProperties]) -> Self {
+        let mut uniform: Self = bytemuck::Zeroable::zeroed();
+        for (dst, src) in uniform.lights.iter_mut().zip(value) {
+            *dst = src.into();
+        }
+        uniform.count = value.len().min(MAX_LIGHTS) as u32;
+
+        uniform
+    }
+}
+
+/// This needs to match the LIGHT_KIND_* constants in light.slang
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightKind {
+    #[default]
+    Point = 0,
+    /// Infinitely far away light that only has a direction, like the sun.
+    Directional = 1,
+}
+
+/// Photometric intensity of a light. Point lights use luminous power or
+/// intensity, and directional lights illuminance.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightIntensity {
+    /// Luminous power, like the rating of a light bulb.
+    Lumens(f32),
+    /// Luminous intensity, the luminous power per solid angle.
+    #[allow(unused)]
+    Candela(f32),
+    /// Illuminance, the luminous power per area that reaches a surface
+    /// facing the light.
+    Lux(f32),
+}
+
+impl LightIntensity {
+    /// The light scaled by `factor`, in the same unit.
+    pub fn scaled(self, factor: f32) -> Self {
+        match self {
+            LightIntensity::Lumens(value) => LightIntensity::Lumens(value * factor),
+            LightIntensity::Candela(value) => LightIntensity::Candela(value * factor),
+            LightIntensity::Lux(value) => LightIntensity::Lux(value * factor),
+        }
+    }
+
+    /// Intensity in candela for point lights and in lux for directional lights,
+    /// which is what the shaders expect. Units that don't fit the kind of
+    /// light are passed on as they are.
+    fn to_shader_units(self, kind: LightKind) -> f32 {
+        match (self, kind) {
+            // A point light sends its power out evenly over the whole sphere
+            (LightIntensity::Lumens(lumens), LightKind::Point) => {
+                lumens / (4.0 * std::f32::consts::PI)
+            }
+            (
+                LightIntensity::Lumens(value)
+                | LightIntensity::Candela(value)
+                | LightIntensity::Lux(value),
+                _,
+            ) => value,
         }
     }
 }
 
 pub struct LightProperties {
+    pub kind: LightKind,
     pub position: cgmath::Vector3<f32>,
+    /// Direction the light travels in. Only used by directional lights.
+    pub direction: cgmath::Vector3<f32>,
     pub color: [f32; 3],
-    pub intensity: f32,
+    pub intensity: LightIntensity,
+    /// Distance at which the light fades out completely, so that it can be
+    /// left out of the clusters further away. Only used by point lights.
+    pub radius: f32,
 }
 
 impl Default for LightProperties {
     fn default() -> Self {
         Self {
+            kind: LightKind::Point,
             position: [0.0, 0.0, 0.0].into(),
-            intensity: 1.0,
+            direction: [0.0, -1.0, 0.0].into(),
+            // About a 60 W incandescent light bulb
+            intensity: LightIntensity::Lumens(800.0),
             color: [1.0, 1.0, 1.0],
+            radius: 20.0,
         }
     }
 }
 
-pub struct Light {
-    pub properties: LightProperties,
-    pub uniform: LightUniform,
+/// Owns the list of all lights in the scene and uploads them as a single uniform
+/// buffer, so that the debug meshes of all lights can be drawn with a single
+/// instanced draw call.
+///
+/// The bind group also has the light masks of the clusters, which the
+/// [`cluster::LightClusterPipeline`] fills every frame, so that shaders only
+/// loop over the lights that reach their cluster. Where those are binned by
+/// the compute shader, every light is also uploaded to a storage buffer, and
+/// the uniform list only has the first [`MAX_LIGHTS`] for the debug meshes.
+pub struct LightManager {
+    pub lights: Vec<LightProperties>,
+    pub uniform: LightListUniform,
+    /// Size of the render in pixels, which needs to be kept up to date for the
+    /// fragments to find their cluster
+    pub screen_size: [f32; 2],
+    /// Shows how many lights every cluster has instead of the lighting. This is
+    /// the luminance of the colors in the exposed render.
+    pub cluster_heatmap: Option<f32>,
 
     // AsBindGroup fields
     buffer: Option<Buffer>,
+    cluster_masks: cluster::ClusterMasks,
     bind_group_layout: BindGroupLayout,
     bind_group: Option<BindGroup>,
 }
 
-impl Light {
-    pub fn new(properties: LightProperties, device: &wgpu::Device) -> Self {
-        let uniform: LightUniform = (&properties).into();
+impl LightManager {
+    pub fn new(
+        lights: Vec<LightProperties>,
+        screen_size: [f32; 2],
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+    ) -> Self {
+        let cluster_masks = cluster::ClusterMasks::new(device, sampler_cache);
+        if lights.len() > cluster_masks.max_lights() {
+            log::warn!(
+                "{} lights were provided but only {} are supported. The rest will be ignored.",
+                lights.len(),
+                cluster_masks.max_lights()
+            );
+        }
 
-        let bind_group_layout = Self::create_bind_group_layout(device, "Light Bind Group Layout");
-        let mut light = Self {
-            properties,
-            uniform,
+        let entries = Self::bind_group_layout_entries()
+            .into_iter()
+            .chain(cluster_masks.bind_group_layout_entries())
+            .collect::<Vec<_>>();
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Light Bind Group Layout"),
+            entries: &entries,
+        });
+        let mut light_manager = Self {
+            lights,
+            uniform: bytemuck::Zeroable::zeroed(),
+            screen_size,
+            cluster_heatmap: None,
             buffer: None,
+            // The grid doesn't depend on the size of the render, so the masks
+            // are only created once
+            cluster_masks,
             bind_group_layout,
             bind_group: None,
         };
 
-        light.init_all(device);
+        light_manager.update_binding_resources();
+        light_manager.init_all(device);
+
+        light_manager
+    }
+
+    /// Number of lights in the uniform light list, which have a debug mesh.
+    pub fn debug_light_count(&self) -> u32 {
+        self.uniform.count.min(MAX_LIGHTS as u32)
+    }
 
-        light
+    /// Number of lights the clusters have room for.
+    pub fn max_lights(&self) -> usize {
+        self.cluster_masks.max_lights()
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        self.buffer
+            .as_ref()
+            .expect("Buffer for LightManager has not been initialized.")
+    }
+
+    /// Light masks of the clusters, with one bit per light.
+    pub fn cluster_masks(&self) -> &cluster::ClusterMasks {
+        &self.cluster_masks
     }
 }
 
-impl AsBindGroup for Light {
+impl AsBindGroup for LightManager {
     fn init_binding_resources(&mut self, device: &wgpu::Device) {
         self.buffer = Some(
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Light VB"),
+                label: Some("Light List Buffer"),
                 contents: bytemuck::cast_slice(&[self.uniform]),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }),
         );
     }
 
+    // Only the light list, the entries of the cluster masks depend on how the
+    // lights are binned, see `ClusterMasks::bind_group_layout_entries`
     fn bind_group_layout_entries() -> Vec<wgpu::BindGroupLayoutEntry> {
         vec![wgpu::BindGroupLayoutEntry {
             binding: 0,
@@ -95,13 +261,17 @@ impl AsBindGroup for Light {
     }
 
     fn init_bind_group(&mut self, device: &wgpu::Device) {
+        let entries = std::iter::once(wgpu::BindGroupEntry {
+            binding: 0,
+            resource: self.buffer().as_entire_binding(),
+        })
+        .chain(self.cluster_masks.bind_group_entries())
+        .collect::<Vec<_>>();
+
         self.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: self.bind_group_layout(),
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: self.buffer.as_ref().unwrap().as_entire_binding(),
-            }],
+            entries: &entries,
         }));
     }
 
@@ -111,14 +281,17 @@ impl AsBindGroup for Light {
 
     fn bind_group(&self) -> &BindGroup {
         if self.bind_group.is_none() {
-            panic!("Bind Group for Light has not been initialized.");
+            panic!("Bind Group for LightManager has not been initialized.");
         }
 
         self.bind_group.as_ref().unwrap()
     }
 
     fn update_binding_resources(&mut self) {
-        self.uniform = (&self.properties).into();
+        self.uniform = self.lights.as_slice().into();
+        self.uniform.count = self.lights.len().min(self.max_lights()) as u32;
+        self.uniform.screen_size = self.screen_size;
+        self.uniform.heatmap_luminance = self.cluster_heatmap.unwrap_or(0.0);
     }
 
     fn queue_write_binding_resources(&mut self, queue: &Queue) {
@@ -129,13 +302,26 @@ impl AsBindGroup for Light {
             0,
             bytemuck::cast_slice(&[self.uniform]),
         );
+
+        if let cluster::ClusterMasks::Storage { lights, .. } = &self.cluster_masks {
+            let uniforms = self
+                .lights
+                .iter()
+                .take(MAX_STORAGE_LIGHTS)
+                .map(LightUniform::from)
+                .collect::<Vec<_>>();
+            if !uniforms.is_empty() {
+                queue.write_buffer(lights, 0, bytemuck::cast_slice(&uniforms));
+            }
+        }
     }
 }
 
 // model.rs
 pub trait DrawLight<'a> {
-    fn draw_light(
+    fn draw_lights(
         &mut self,
+        light_count: u32,
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
@@ -145,14 +331,16 @@ impl<'a, 'b> DrawLight<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a,
 {
-    fn draw_light(
+    fn draw_lights(
         &mut self,
+        light_count: u32,
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
         const VERTEX_COUNT: u32 = 32;
         self.set_bind_group(0, camera_bind_group, &[]);
         self.set_bind_group(1, light_bind_group, &[]);
-        self.draw(0..VERTEX_COUNT, 0..1);
+        // one instance per light. The shader picks its light with the instance index
+        self.draw(0..VERTEX_COUNT, 0..light_count);
     }
 }
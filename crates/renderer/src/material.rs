@@ -1,17 +1,60 @@
 use std::sync::Arc;
 
+use wgpu::util::DeviceExt;
+
 use crate::{
     texture::{self, FallbackTextures},
     wgpu_traits::AsBindGroup,
 };
 
+/// Material parameters that are not part of the textures themselves.
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialProperties {
+    /// LOD bias of the diffuse, normal and ARM textures, taken from their
+    /// import options.
+    pub lod_bias: [f32; 3],
+    /// Strength of the normal map. 0 gives a flat surface.
+    pub normal_scale: f32,
+}
+
+impl Default for MaterialProperties {
+    fn default() -> Self {
+        Self {
+            lod_bias: [0.0; 3],
+            normal_scale: 1.0,
+        }
+    }
+}
+
+/// This needs to match MaterialUniform in lit.slang
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+    lod_bias: [f32; 4],
+    normal_scale: f32,
+    _padding: [f32; 3],
+}
+
+impl From<&MaterialProperties> for MaterialUniform {
+    fn from(value: &MaterialProperties) -> Self {
+        let [diffuse, normal, arm] = value.lod_bias;
+        Self {
+            lod_bias: [diffuse, normal, arm, 0.0],
+            normal_scale: value.normal_scale,
+            _padding: [0.0; 3],
+        }
+    }
+}
+
 pub struct Material {
     pub name: String,
     pub diffuse_texture: Arc<texture::Texture>,
     pub normal_texture: Arc<texture::Texture>,
     pub arm_texture: Arc<texture::Texture>,
+    pub properties: MaterialProperties,
 
     // AsBindGroup fields
+    uniform_buffer: Option<wgpu::Buffer>,
     bind_group: Option<wgpu::BindGroup>,
     bind_group_layout: wgpu::BindGroupLayout,
 }
@@ -23,6 +66,7 @@ impl Material {
         diffuse_texture: Arc<texture::Texture>,
         normal_texture: Arc<texture::Texture>,
         arm_texture: Arc<texture::Texture>,
+        properties: MaterialProperties,
     ) -> Self {
         let bind_group_layout = Self::create_bind_group_layout(device, name);
         let mut material = Self {
@@ -30,11 +74,13 @@ impl Material {
             diffuse_texture,
             normal_texture,
             arm_texture,
+            properties,
+            uniform_buffer: None,
             bind_group: None,
             bind_group_layout,
         };
 
-        material.init_bind_group(device);
+        material.init_all(device);
 
         material
     }
@@ -51,11 +97,13 @@ impl Material {
             diffuse_texture,
             normal_texture,
             arm_texture,
+            properties: Default::default(),
+            uniform_buffer: None,
             bind_group: None,
             bind_group_layout,
         };
 
-        material.init_bind_group(device);
+        material.init_all(device);
 
         material
     }
@@ -67,6 +115,16 @@ impl AsBindGroup for Material {
             wgpu::BindGroupLayoutEntry {
                 binding: 0,
                 visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     multisampled: false,
                     view_dimension: wgpu::TextureViewDimension::D2,
@@ -75,7 +133,7 @@ impl AsBindGroup for Material {
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 1,
+                binding: 2,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 // This should match the filterable field of the
                 // corresponding Texture entry above.
@@ -83,7 +141,7 @@ impl AsBindGroup for Material {
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 2,
+                binding: 3,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     multisampled: false,
@@ -93,7 +151,7 @@ impl AsBindGroup for Material {
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 3,
+                binding: 4,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 // This should match the filterable field of the
                 // corresponding Texture entry above.
@@ -101,7 +159,7 @@ impl AsBindGroup for Material {
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 4,
+                binding: 5,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     multisampled: false,
@@ -111,7 +169,7 @@ impl AsBindGroup for Material {
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
-                binding: 5,
+                binding: 6,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 // This should match the filterable field of the
                 // corresponding Texture entry above.
@@ -128,26 +186,30 @@ impl AsBindGroup for Material {
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&self.diffuse_texture.view),
+                    resource: self.uniform_buffer.as_ref().unwrap().as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&self.diffuse_texture.sampler),
+                    resource: wgpu::BindingResource::TextureView(&self.diffuse_texture.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&self.normal_texture.view),
+                    resource: wgpu::BindingResource::Sampler(&self.diffuse_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&self.normal_texture.sampler),
+                    resource: wgpu::BindingResource::TextureView(&self.normal_texture.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 4,
-                    resource: wgpu::BindingResource::TextureView(&self.arm_texture.view),
+                    resource: wgpu::BindingResource::Sampler(&self.normal_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
                     binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&self.arm_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
                     resource: wgpu::BindingResource::Sampler(&self.arm_texture.sampler),
                 },
             ],
@@ -155,8 +217,14 @@ impl AsBindGroup for Material {
     }
 
     fn init_binding_resources(&mut self, device: &wgpu::Device) {
-        let _ = device;
-        // Binding resources are already initialized in the Texture fields
+        // The texture binding resources are already initialized in the Texture fields
+        self.uniform_buffer = Some(
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} Uniform Buffer", self.name)),
+                contents: bytemuck::cast_slice(&[MaterialUniform::from(&self.properties)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }),
+        );
     }
 
     fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
@@ -177,6 +245,10 @@ impl AsBindGroup for Material {
     fn update_binding_resources(&mut self) {}
 
     fn queue_write_binding_resources(&mut self, queue: &wgpu::Queue) {
-        let _ = queue;
+        queue.write_buffer(
+            self.uniform_buffer.as_ref().unwrap(),
+            0,
+            bytemuck::cast_slice(&[MaterialUniform::from(&self.properties)]),
+        );
     }
 }
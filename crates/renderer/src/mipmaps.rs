@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::wgpu_include_slang_shader;
+
+/// How the levels of a generated mip chain are filtered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MipmapMode {
+    /// Box filter of the color, done in linear space for sRGB textures.
+    Color,
+    /// Box filter of tangent space normals stored as x and y, renormalized for
+    /// every level.
+    NormalMap,
+}
+
+impl MipmapMode {
+    fn entry_point(self) -> &'static str {
+        match self {
+            MipmapMode::Color => "fs_color",
+            MipmapMode::NormalMap => "fs_normal",
+        }
+    }
+}
+
+struct BlitResources {
+    shader: wgpu::ShaderModule,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline_layout: wgpu::PipelineLayout,
+}
+
+impl BlitResources {
+    fn new(device: &wgpu::Device) -> Self {
+        let shader = device.create_shader_module(wgpu_include_slang_shader!("mipmap"));
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Mipmap Bind Group Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Mipmap Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        Self {
+            shader,
+            bind_group_layout,
+            pipeline_layout,
+        }
+    }
+}
+
+/// Fills in the mip chain of textures that only come with their first level,
+/// by rendering every level from the one before it. This uses a render
+/// pipeline instead of a compute shader, so it also works on WebGL.
+///
+/// Everything is created on first use, and there is one pipeline per format and
+/// mode, since the render target format is part of the pipeline.
+#[derive(Default)]
+pub struct MipmapGenerator {
+    resources: OnceLock<BlitResources>,
+    pipelines: Mutex<HashMap<(wgpu::TextureFormat, MipmapMode), wgpu::RenderPipeline>>,
+}
+
+impl MipmapGenerator {
+    /// Whether mips of textures with this format can be generated. The format
+    /// has to be both filterable and renderable, which rules out all block
+    /// compressed formats.
+    pub fn supports_format(device: &wgpu::Device, format: wgpu::TextureFormat) -> bool {
+        let features = format.guaranteed_format_features(device.features());
+        !format.is_compressed()
+            && features
+                .allowed_usages
+                .contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+            && features
+                .flags
+                .contains(wgpu::TextureFormatFeatureFlags::FILTERABLE)
+    }
+
+    /// Generates all mips after the first one, for every layer of the texture.
+    /// The texture needs to be a 2D texture with a supported format, and needs
+    /// the `TEXTURE_BINDING` and `RENDER_ATTACHMENT` usages.
+    pub fn generate(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        mode: MipmapMode,
+    ) {
+        let resources = self.resources.get_or_init(|| BlitResources::new(device));
+        let format = texture.format();
+
+        let mut pipelines = self.pipelines.lock().unwrap();
+        let pipeline = pipelines
+            .entry((format, mode))
+            .or_insert_with(|| create_pipeline(device, resources, format, mode));
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+
+        // Passes are recorded in order, so each one reads the mip that was
+        // rendered by the previous one
+        for layer in 0..texture.depth_or_array_layers() {
+            for mip in 1..texture.mip_level_count() {
+                let source_view = mip_layer_view(texture, mip - 1, layer);
+                let target_view = mip_layer_view(texture, mip, layer);
+
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Mipmap Bind Group"),
+                    layout: &resources.bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source_view),
+                    }],
+                });
+
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Mipmap Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &target_view,
+                        depth_slice: None,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.draw(0..3, 0..1);
+            }
+        }
+
+        queue.submit(Some(encoder.finish()));
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    resources: &BlitResources,
+    format: wgpu::TextureFormat,
+    mode: MipmapMode,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(&format!("Mipmap Pipeline ({format:?}, {mode:?})")),
+        layout: Some(&resources.pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &resources.shader,
+            entry_point: Some("vs_main"),
+            buffers: &[],
+            compilation_options: Default::default(),
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &resources.shader,
+            entry_point: Some(mode.entry_point()),
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            compilation_options: Default::default(),
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+        cache: None,
+    })
+}
+
+/// View of a single mip of a single layer. The view has the format of the
+/// texture, so sRGB textures are decoded when sampled and encoded when rendered to.
+fn mip_layer_view(texture: &wgpu::Texture, mip_level: u32, layer: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some("Mipmap View"),
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_mip_level: mip_level,
+        mip_level_count: Some(1),
+        base_array_layer: layer,
+        array_layer_count: Some(1),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,176 @@
+use cgmath::{Deg, InnerSpace, Rad, Vector3};
+
+/// Angular radius of the sun as seen from the earth.
+const SUN_ANGULAR_RADIUS: f32 = 0.00465;
+
+/// Settings of the Preetham analytic daylight model.
+/// Source: "A Practical Analytic Model for Daylight" by Preetham, Shirley and Smits
+#[derive(Clone, Copy, Debug)]
+pub struct PreethamSky {
+    /// Angle of the sun above the horizon. The model is only valid while the sun
+    /// is above the horizon, so this gets clamped to [0, 90] degrees.
+    pub sun_elevation: Deg<f32>,
+    /// Angle of the sun around the Y axis, starting from +X towards +Z.
+    pub sun_azimuth: Deg<f32>,
+    /// Amount of haze in the atmosphere, from 2 (very clear) to 10 (hazy).
+    pub turbidity: f32,
+    pub ground_albedo: [f32; 3],
+    /// Illuminance of the sun in lux at noon, before the atmosphere absorbs
+    /// some of it.
+    pub sun_intensity: f32,
+    /// Whether the sun should drive a directional light in the light list.
+    pub drive_sun_light: bool,
+}
+
+impl Default for PreethamSky {
+    fn default() -> Self {
+        Self {
+            sun_elevation: Deg(30.0),
+            sun_azimuth: Deg(45.0),
+            turbidity: 3.0,
+            ground_albedo: [0.3, 0.3, 0.3],
+            sun_intensity: 110_000.0,
+            drive_sun_light: true,
+        }
+    }
+}
+
+impl PreethamSky {
+    fn sun_zenith_angle(&self) -> f32 {
+        let elevation = Rad::from(self.sun_elevation).0;
+        // Keep the sun slightly above the horizon, where the model breaks down
+        std::f32::consts::FRAC_PI_2 - elevation.clamp(0.01, std::f32::consts::FRAC_PI_2)
+    }
+
+    /// Direction towards the sun.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let zenith = self.sun_zenith_angle();
+        let azimuth = Rad::from(self.sun_azimuth).0;
+        Vector3::new(
+            zenith.sin() * azimuth.cos(),
+            zenith.cos(),
+            zenith.sin() * azimuth.sin(),
+        )
+        .normalize()
+    }
+
+    /// Color of the sun light after going through the atmosphere. This is a rough
+    /// approximation of the extinction, which gets stronger as the sun gets lower
+    /// and the turbidity higher.
+    pub fn sun_color(&self) -> [f32; 3] {
+        let zenith_degrees = self.sun_zenith_angle().to_degrees();
+        // Kasten and Young's relative air mass
+        let air_mass = 1.0
+            / (zenith_degrees.to_radians().cos()
+                + 0.50572 * (96.07995 - zenith_degrees).powf(-1.6364));
+
+        let haze = self.turbidity / 3.0;
+        let extinction = [0.04 * haze, 0.08 * haze, 0.18 * haze];
+        extinction.map(|beta| (-beta * air_mass).exp())
+    }
+
+    pub fn to_uniform(self) -> PreethamUniform {
+        let t = self.turbidity;
+        let theta_s = self.sun_zenith_angle();
+
+        // Perez distribution coefficients for Y, x and y (Appendix A.2 of the paper)
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+            ],
+            [
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+            ],
+            [
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+            ],
+            [
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+            ],
+            [
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        // Zenith luminance (in kcd/m², converted to cd/m²) and chromaticity
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * theta_s);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192) * 1000.0;
+        let theta = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+        let chromaticity = |matrix: [[f32; 4]; 3]| {
+            let row = |coefficients: [f32; 4]| {
+                coefficients
+                    .iter()
+                    .zip(theta)
+                    .map(|(c, theta)| c * theta)
+                    .sum::<f32>()
+            };
+            t * t * row(matrix[0]) + t * row(matrix[1]) + row(matrix[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        // Divide by the distribution at the zenith up front, so the shader only has
+        // to evaluate it once per direction
+        let zenith = [zenith_luminance, zenith_x, zenith_y];
+        let mut zenith_over_perez = [0.0; 4];
+        for channel in 0..3 {
+            let [a, b, c, d, e] = perez.map(|coefficients| coefficients[channel]);
+            let perez_at_zenith =
+                (1.0 + a * b.exp()) * (1.0 + c * (d * theta_s).exp() + e * theta_s.cos().powi(2));
+            zenith_over_perez[channel] = zenith[channel] / perez_at_zenith;
+        }
+
+        let sun_direction = self.sun_direction();
+        let sun_solid_angle = 2.0 * std::f32::consts::PI * (1.0 - SUN_ANGULAR_RADIUS.cos());
+        let sun_radiance = self
+            .sun_color()
+            .map(|channel| channel * self.sun_intensity / sun_solid_angle);
+
+        PreethamUniform {
+            perez: perez.map(|[y, x, z]| [y, x, z, 0.0]),
+            zenith: zenith_over_perez,
+            sun_direction: [
+                sun_direction.x,
+                sun_direction.y,
+                sun_direction.z,
+                SUN_ANGULAR_RADIUS.cos(),
+            ],
+            sun_radiance: [sun_radiance[0], sun_radiance[1], sun_radiance[2], 0.0],
+            ground_albedo: [
+                self.ground_albedo[0],
+                self.ground_albedo[1],
+                self.ground_albedo[2],
+                0.0,
+            ],
+        }
+    }
+}
+
+/// This needs to match PreethamUniform in preetham.slang
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PreethamUniform {
+    pub perez: [[f32; 4]; 5],
+    pub zenith: [f32; 4],
+    pub sun_direction: [f32; 4],
+    pub sun_radiance: [f32; 4],
+    pub ground_albedo: [f32; 4],
+}
@@ -1,11 +1,46 @@
-use crate::material::Material;
+use crate::asset_server::AssetServer;
+use crate::material::{Material, MaterialProperties};
+use crate::mipmaps::{MipmapGenerator, MipmapMode};
+use crate::sampler_cache::SamplerCache;
 use crate::sky::ShCoefficients;
-use crate::texture::{FallbackTextures, TextureImportOptions};
+use crate::texture::{FallbackTextures, TextureError, TextureImportOptions};
+use crate::texture_streaming::StreamingTexture;
 use crate::{model, texture};
 use std::io::{BufReader, Cursor};
 use std::sync::Arc;
 use wgpu::util::DeviceExt;
 
+/// Everything that can go wrong while loading a resource file.
+#[derive(Debug, thiserror::Error)]
+pub enum ResourceError {
+    #[error("Failed to load {path}: {reason}")]
+    MissingFile { path: String, reason: String },
+    #[error("Failed to parse {path}: {reason}")]
+    InvalidFile { path: String, reason: String },
+    #[error("Failed to load texture {path}: {source}")]
+    Texture {
+        path: String,
+        #[source]
+        source: TextureError,
+    },
+}
+
+impl ResourceError {
+    fn missing_file(path: &str, reason: impl ToString) -> Self {
+        Self::MissingFile {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    fn invalid_file(path: &str, reason: impl ToString) -> Self {
+        Self::InvalidFile {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
 #[cfg(target_arch = "wasm32")]
 fn format_url(file_name: &str) -> reqwest::Url {
     let window = web_sys::window().unwrap();
@@ -17,12 +52,22 @@ fn format_url(file_name: &str) -> reqwest::Url {
     base.join(&file_path).unwrap()
 }
 
-pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
+#[cfg(target_arch = "wasm32")]
+async fn fetch(file_name: &str) -> Result<reqwest::Response, ResourceError> {
+    let url = format_url(file_name);
+    reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| ResourceError::missing_file(file_name, err))
+}
+
+pub async fn load_string(file_name: &str) -> Result<String, ResourceError> {
     #[cfg(target_arch = "wasm32")]
-    let txt = {
-        let url = format_url(file_name);
-        reqwest::get(url).await?.text().await?
-    };
+    let txt = fetch(file_name)
+        .await?
+        .text()
+        .await
+        .map_err(|err| ResourceError::missing_file(file_name, err))?;
 
     #[cfg(not(target_arch = "wasm32"))]
     let txt = {
@@ -30,18 +75,20 @@ pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
         let path = std::path::Path::new(env!("OUT_DIR"))
             .join("res")
             .join(file_name);
-        std::fs::read_to_string(path)?
+        std::fs::read_to_string(path).map_err(|err| ResourceError::missing_file(file_name, err))?
     };
 
     Ok(txt)
 }
 
-pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
+pub async fn load_binary(file_name: &str) -> Result<Vec<u8>, ResourceError> {
     #[cfg(target_arch = "wasm32")]
-    let data = {
-        let url = format_url(file_name);
-        reqwest::get(url).await?.bytes().await?.to_vec()
-    };
+    let data = fetch(file_name)
+        .await?
+        .bytes()
+        .await
+        .map_err(|err| ResourceError::missing_file(file_name, err))?
+        .to_vec();
 
     #[cfg(not(target_arch = "wasm32"))]
     let data = {
@@ -49,39 +96,199 @@ pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
         let path = std::path::Path::new(env!("OUT_DIR"))
             .join("res")
             .join(file_name);
-        std::fs::read(path)?
+        std::fs::read(path).map_err(|err| ResourceError::missing_file(file_name, err))?
     };
 
     Ok(data)
 }
 
-pub async fn load_sh_coefficients(file_name: &str) -> anyhow::Result<ShCoefficients> {
+pub async fn load_sh_coefficients(file_name: &str) -> Result<ShCoefficients, ResourceError> {
+    let data = load_binary(file_name).await?;
+
+    postcard::from_bytes(&data).map_err(|err| ResourceError::invalid_file(file_name, err))
+}
+
+/// Loads a high dynamic range image (EXR or Radiance HDR) as 32 bit float RGBA.
+pub async fn load_hdr_image(file_name: &str) -> Result<image::Rgba32FImage, ResourceError> {
     let data = load_binary(file_name).await?;
 
-    Ok(postcard::from_bytes(&data)?)
+    let image = image::load_from_memory(&data)
+        .map_err(|err| ResourceError::invalid_file(file_name, err))?;
+    Ok(image.to_rgba32f())
 }
 
 pub async fn load_texture<'a>(
     file_name: &'a str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+    sampler_cache: &SamplerCache,
+    mipmap_generator: Option<&MipmapGenerator>,
     mut options: TextureImportOptions<'a>,
-) -> anyhow::Result<texture::Texture> {
+) -> Result<texture::Texture, ResourceError> {
     let data = load_binary(file_name).await?;
 
     if options.label.is_none() {
         options.label = Some(file_name);
     }
 
-    texture::Texture::from_bytes(device, queue, &data, options)
+    texture::Texture::from_bytes(
+        device,
+        queue,
+        sampler_cache,
+        mipmap_generator,
+        &data,
+        options,
+    )
+    .map_err(|source| ResourceError::Texture {
+        path: file_name.to_string(),
+        source,
+    })
+}
+
+/// Loads a texture that gets its mips uploaded over the next frames, see
+/// [`StreamingTexture`].
+pub async fn load_streaming_texture<'a>(
+    file_name: &'a str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    sampler_cache: &SamplerCache,
+    mut options: TextureImportOptions<'a>,
+) -> Result<StreamingTexture, ResourceError> {
+    let data = load_binary(file_name).await?;
+
+    if options.label.is_none() {
+        options.label = Some(file_name);
+    }
+
+    StreamingTexture::new(device, queue, sampler_cache, data, options).map_err(|source| {
+        ResourceError::Texture {
+            path: file_name.to_string(),
+            source,
+        }
+    })
+}
+
+/// A texture map statement of an MTL file, like `map_Kd -clamp on wood.ktx2`.
+/// Only the options that have an equivalent in the renderer are kept.
+#[derive(Debug, Default)]
+struct MtlTextureMap {
+    file_name: String,
+    /// `-clamp on`: clamp the texture coordinates instead of repeating the texture
+    clamp: bool,
+    /// `-bm`: bump multiplier, used as the strength of normal maps
+    bump_multiplier: Option<f32>,
+    /// `-boost`: sharpens mip-mapped textures, which maps onto a negative LOD bias
+    boost: Option<f32>,
+}
+
+impl MtlTextureMap {
+    fn parse(map: &str) -> Self {
+        let tokens = map.split_whitespace().collect::<Vec<_>>();
+        let mut texture_map = Self::default();
+
+        let mut i = 0;
+        while i < tokens.len() && tokens[i].starts_with('-') {
+            let option = tokens[i];
+            let argument = tokens.get(i + 1).copied();
+            let number = argument.and_then(|arg| arg.parse::<f32>().ok());
+            i += 1;
+
+            match option {
+                "-clamp" => {
+                    texture_map.clamp = argument == Some("on");
+                    i += 1;
+                }
+                "-bm" | "-boost" => {
+                    if number.is_none() {
+                        log::warn!(
+                            "Expected a number after {option} in MTL file, got {argument:?}."
+                        );
+                    }
+                    if option == "-bm" {
+                        texture_map.bump_multiplier = number;
+                    } else {
+                        texture_map.boost = number;
+                    }
+                    i += 1;
+                }
+                "-blendu" | "-blendv" | "-cc" | "-imfchan" | "-texres" | "-type" => i += 1,
+                "-mm" => i += 2,
+                // Offset, scale and turbulence take 1 to 3 numbers
+                "-o" | "-s" | "-t" => {
+                    let count = tokens[i..]
+                        .iter()
+                        .take(3)
+                        .take_while(|arg| arg.parse::<f32>().is_ok())
+                        .count();
+                    i += count;
+                }
+                _ => log::warn!("Ignoring unknown texture option {option} in MTL file."),
+            }
+        }
+
+        // Whatever is left is the file name, which may contain spaces
+        texture_map.file_name = tokens.get(i..).unwrap_or_default().join(" ");
+        texture_map
+    }
+
+    fn import_options(&self, mipmaps: MipmapMode) -> TextureImportOptions<'_> {
+        // MTL textures repeat unless they ask to be clamped
+        let address_mode = if self.clamp {
+            wgpu::AddressMode::ClampToEdge
+        } else {
+            wgpu::AddressMode::Repeat
+        };
+
+        TextureImportOptions {
+            label: Some(&self.file_name),
+            anisotropy_clamp: 16,
+            lod_bias: -self.boost.unwrap_or(0.0),
+            generate_mipmaps: Some(mipmaps),
+            ..Default::default()
+        }
+        .with_address_mode(address_mode)
+    }
+}
+
+/// Loads the texture of an MTL texture map statement, or returns the fallback
+/// texture if the material doesn't have one or it fails to load.
+async fn load_mtl_texture(
+    map: &str,
+    mipmaps: MipmapMode,
+    fallback: Arc<texture::Texture>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    asset_server: &AssetServer,
+) -> (Arc<texture::Texture>, MtlTextureMap) {
+    let texture_map = MtlTextureMap::parse(map);
+    if texture_map.file_name.is_empty() {
+        return (fallback, texture_map);
+    }
+
+    match asset_server
+        .load_texture(
+            &texture_map.file_name,
+            device,
+            queue,
+            texture_map.import_options(mipmaps),
+        )
+        .await
+    {
+        Ok(texture) => (texture, texture_map),
+        Err(err) => {
+            log::warn!("{err}, using the fallback texture instead");
+            (fallback, texture_map)
+        }
+    }
 }
 
 pub async fn load_model(
     file_name: &str,
     queue: &wgpu::Queue,
     device: &wgpu::Device,
+    asset_server: &AssetServer,
     fallback_textures: &FallbackTextures,
-) -> anyhow::Result<model::Model> {
+) -> Result<model::Model, ResourceError> {
     let obj_text = load_string(file_name).await?;
     let obj_cursor = Cursor::new(obj_text);
     let mut obj_reader = BufReader::new(obj_cursor);
@@ -94,31 +301,60 @@ pub async fn load_model(
             ..Default::default()
         },
         |p| async move {
-            let mat_text = load_string(&p).await.unwrap();
+            let mat_text = load_string(&p).await.map_err(|err| {
+                log::warn!("{err}");
+                tobj::LoadError::OpenFileFailed
+            })?;
             tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
         },
     )
-    .await?;
+    .await
+    .map_err(|err| ResourceError::invalid_file(file_name, err))?;
 
-    let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = if m.diffuse_texture.is_empty() {
-            fallback_textures.base_color()
-        } else {
-            Arc::new(load_texture(&m.diffuse_texture, device, queue, Default::default()).await?)
-        };
+    // A model without its materials is still worth showing, it just gets the
+    // default material below
+    let obj_materials = obj_materials.unwrap_or_else(|err| {
+        log::warn!("Failed to load the materials of {file_name}: {err}");
+        Vec::new()
+    });
 
-        let normal_texture = if m.normal_texture.is_empty() {
-            fallback_textures.normal()
-        } else {
-            Arc::new(load_texture(&m.normal_texture, device, queue, Default::default()).await?)
-        };
+    let mut materials = Vec::new();
+    for m in obj_materials {
+        let (diffuse_texture, diffuse_map) = load_mtl_texture(
+            &m.diffuse_texture,
+            MipmapMode::Color,
+            fallback_textures.base_color(),
+            device,
+            queue,
+            asset_server,
+        )
+        .await;
+
+        let (normal_texture, normal_map) = load_mtl_texture(
+            &m.normal_texture,
+            MipmapMode::NormalMap,
+            fallback_textures.normal(),
+            device,
+            queue,
+            asset_server,
+        )
+        .await;
 
         // we (ab)use the ambient texture and treat it as the ARM texture
-        let arm_texture = if m.ambient_texture.is_empty() {
-            fallback_textures.arm()
-        } else {
-            Arc::new(load_texture(&m.ambient_texture, device, queue, Default::default()).await?)
+        let (arm_texture, arm_map) = load_mtl_texture(
+            &m.ambient_texture,
+            MipmapMode::Color,
+            fallback_textures.arm(),
+            device,
+            queue,
+            asset_server,
+        )
+        .await;
+
+        let properties = MaterialProperties {
+            lod_bias: [&diffuse_map, &normal_map, &arm_map]
+                .map(|map| map.import_options(MipmapMode::Color).lod_bias),
+            normal_scale: normal_map.bump_multiplier.unwrap_or(1.0),
         };
 
         materials.push(Material::new(
@@ -127,6 +363,7 @@ pub async fn load_model(
             diffuse_texture,
             normal_texture,
             arm_texture,
+            properties,
         ));
     }
 
@@ -148,13 +385,18 @@ pub async fn load_model(
                             m.mesh.normals[i * 3 + 2],
                         ]
                     };
+                    let tex_coords = if m.mesh.texcoords.is_empty() {
+                        [0.0, 0.0]
+                    } else {
+                        [m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1]]
+                    };
                     model::ModelVertex {
                         position: [
                             m.mesh.positions[i * 3],
                             m.mesh.positions[i * 3 + 1],
                             m.mesh.positions[i * 3 + 2],
                         ],
-                        tex_coords: [m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1]],
+                        tex_coords,
                         normal,
                         // tangent and bitangent are included in some 3d files, but for .obj
                         // files, we will need to calculate them ourselves
@@ -0,0 +1,90 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// The parts of a [`wgpu::SamplerDescriptor`] that affect sampling. The label
+/// is left out so that samplers with different labels can still be shared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerKey {
+    address_modes: [wgpu::AddressMode; 3],
+    filters: [wgpu::FilterMode; 3],
+    lod_clamp_bits: [u32; 2],
+    compare: Option<wgpu::CompareFunction>,
+    anisotropy_clamp: u16,
+    border_color: Option<wgpu::SamplerBorderColor>,
+}
+
+impl From<&wgpu::SamplerDescriptor<'_>> for SamplerKey {
+    fn from(value: &wgpu::SamplerDescriptor<'_>) -> Self {
+        Self {
+            address_modes: [
+                value.address_mode_u,
+                value.address_mode_v,
+                value.address_mode_w,
+            ],
+            filters: [value.mag_filter, value.min_filter, value.mipmap_filter],
+            lod_clamp_bits: [value.lod_min_clamp.to_bits(), value.lod_max_clamp.to_bits()],
+            compare: value.compare,
+            anisotropy_clamp: value.anisotropy_clamp,
+            border_color: value.border_color,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SamplerCacheInner {
+    samplers: HashMap<SamplerKey, Arc<wgpu::Sampler>>,
+    request_count: usize,
+}
+
+/// Hands out shared samplers, so that textures with the same sampler settings
+/// don't each create their own. Samplers are tiny, so they are kept alive for
+/// as long as the cache is.
+#[derive(Default)]
+pub struct SamplerCache {
+    inner: Mutex<SamplerCacheInner>,
+}
+
+impl SamplerCache {
+    /// Returns the sampler matching `desc`, creating it the first time.
+    pub fn get(&self, device: &wgpu::Device, desc: &wgpu::SamplerDescriptor) -> Arc<wgpu::Sampler> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.request_count += 1;
+
+        let key = SamplerKey::from(desc);
+        if let Some(sampler) = inner.samplers.get(&key) {
+            return sampler.clone();
+        }
+
+        let sampler = Arc::new(device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shared Sampler"),
+            ..desc.clone()
+        }));
+        inner.samplers.insert(key, sampler.clone());
+
+        #[cfg(debug_assertions)]
+        log::debug!("Created shared sampler #{}: {key:?}", inner.samplers.len());
+
+        sampler
+    }
+
+    /// Logs how many samplers were created and how many were shared.
+    /// Only does anything in debug builds.
+    pub fn log_stats(&self) {
+        #[cfg(debug_assertions)]
+        {
+            let inner = self.inner.lock().unwrap();
+            let created = inner.samplers.len();
+            // This only counts the cache itself, the driver side of a sampler is
+            // not visible to wgpu
+            let memory = inner.samplers.capacity()
+                * (std::mem::size_of::<SamplerKey>() + std::mem::size_of::<Arc<wgpu::Sampler>>());
+            log::debug!(
+                "Sampler cache: created {created} samplers for {} requests ({} shared), using {memory} bytes",
+                inner.request_count,
+                inner.request_count - created,
+            );
+        }
+    }
+}
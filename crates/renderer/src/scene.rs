@@ -0,0 +1,287 @@
+use cgmath::{Matrix4, One, Quaternion, SquareMatrix, Vector3, Zero};
+
+use crate::{
+    instance::{InstanceBuffer, InstanceRaw},
+    light::LightManager,
+};
+
+/// Local transform of a node, relative to its parent.
+/// This follows the same Translation/Rotation/Scale split as glTF nodes.
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn from_translation<V: Into<Vector3<f32>>>(translation: V) -> Self {
+        Self {
+            translation: translation.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_matrix(self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+/// What a node represents in the scene. The indices refer to the lists owned by the
+/// renderer (models and lights), so the scene graph only drives their transforms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attachment {
+    /// An instance of the model at this index.
+    Mesh { model: usize },
+    /// The light at this index in the [`LightManager`].
+    Light { light: usize },
+    /// The main camera. Children of this node follow the camera around.
+    Camera,
+}
+
+/// Slot of a node, and how many nodes were removed from that slot before it.
+/// Slots are reused, so the generation keeps the IDs of removed nodes from
+/// referring to the nodes that took their place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index: usize,
+    generation: u32,
+}
+
+pub struct Node {
+    pub name: String,
+    pub local: Transform,
+    pub attachment: Option<Attachment>,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    world: Matrix4<f32>,
+    /// World matrix of the update before that, for motion vectors
+    previous_world: Matrix4<f32>,
+    /// New nodes have no previous world matrix until their first update
+    has_world: bool,
+}
+
+impl Node {
+    /// World matrix as of the last call to [`SceneGraph::update_world_transforms`].
+    pub fn world_matrix(&self) -> Matrix4<f32> {
+        self.world
+    }
+
+    /// World matrix as of the call before the last one to
+    /// [`SceneGraph::update_world_transforms`], or the current one if the node
+    /// has only been updated once.
+    pub fn previous_world_matrix(&self) -> Matrix4<f32> {
+        self.previous_world
+    }
+
+    pub fn world_position(&self) -> Vector3<f32> {
+        self.world.w.truncate()
+    }
+}
+
+/// A hierarchy of nodes with local transforms. World matrices are propagated from
+/// the roots down to the leaves, and are then used to fill the instance buffers of
+/// models and the positions of lights.
+///
+/// Nodes are stored in slots, so a [`NodeId`] stays valid until its node is removed.
+#[derive(Default)]
+pub struct SceneGraph {
+    nodes: Vec<Slot>,
+    roots: Vec<NodeId>,
+}
+
+#[derive(Default)]
+struct Slot {
+    /// Incremented whenever the node is removed
+    generation: u32,
+    node: Option<Node>,
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_node(
+        &mut self,
+        name: &str,
+        local: Transform,
+        attachment: Option<Attachment>,
+        parent: Option<NodeId>,
+    ) -> NodeId {
+        let node = Node {
+            name: name.to_string(),
+            local,
+            attachment,
+            parent: None,
+            children: Vec::new(),
+            world: Matrix4::identity(),
+            previous_world: Matrix4::identity(),
+            has_world: false,
+        };
+
+        // reuse an empty slot if there is one
+        let index = match self.nodes.iter().position(|slot| slot.node.is_none()) {
+            Some(index) => index,
+            None => {
+                self.nodes.push(Slot::default());
+                self.nodes.len() - 1
+            }
+        };
+        let slot = &mut self.nodes[index];
+        slot.node = Some(node);
+        let id = NodeId {
+            index,
+            generation: slot.generation,
+        };
+
+        self.roots.push(id);
+        self.set_parent(id, parent);
+
+        id
+    }
+
+    /// Removes a node along with all of its descendants.
+    pub fn remove_node(&mut self, id: NodeId) {
+        self.detach(id);
+
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let slot = &mut self.nodes[id.index];
+            if slot.generation == id.generation
+                && let Some(node) = slot.node.take()
+            {
+                slot.generation += 1;
+                stack.extend(node.children);
+            }
+        }
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        let slot = &self.nodes[id.index];
+        slot.node
+            .as_ref()
+            .filter(|_| slot.generation == id.generation)
+            .expect("Scene node has been removed.")
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        let slot = &mut self.nodes[id.index];
+        slot.node
+            .as_mut()
+            .filter(|_| slot.generation == id.generation)
+            .expect("Scene node has been removed.")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.nodes.iter().enumerate().filter_map(|(index, slot)| {
+            let id = NodeId {
+                index,
+                generation: slot.generation,
+            };
+            slot.node.as_ref().map(|node| (id, node))
+        })
+    }
+
+    fn is_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut current = Some(id);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            current = self.node(id).parent;
+        }
+
+        false
+    }
+
+    fn detach(&mut self, id: NodeId) {
+        match self.node(id).parent {
+            Some(parent) => self.node_mut(parent).children.retain(|child| *child != id),
+            None => self.roots.retain(|root| *root != id),
+        }
+        self.node_mut(id).parent = None;
+    }
+
+    /// Moves a node (and its descendants) under a new parent, or makes it a root
+    /// node when `parent` is `None`. The local transform is kept as is.
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) {
+        if let Some(parent) = parent
+            && self.is_ancestor(id, parent)
+        {
+            log::error!(
+                "Cannot parent scene node '{}' to its own descendant '{}'.",
+                self.node(id).name,
+                self.node(parent).name
+            );
+            return;
+        }
+
+        self.detach(id);
+
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id),
+        }
+        self.node_mut(id).parent = parent;
+    }
+
+    /// Propagates the local transforms down the hierarchy to compute world matrices.
+    /// This should happen once per frame, since the previous world matrices are
+    /// those of the call before.
+    pub fn update_world_transforms(&mut self) {
+        let mut stack = self
+            .roots
+            .iter()
+            .map(|root| (*root, Matrix4::identity()))
+            .collect::<Vec<_>>();
+
+        while let Some((id, parent_world)) = stack.pop() {
+            let node = self.node_mut(id);
+            let world = parent_world * node.local.to_matrix();
+            node.previous_world = if node.has_world { node.world } else { world };
+            node.world = world;
+            node.has_world = true;
+
+            stack.extend(node.children.iter().map(|child| (*child, world)));
+        }
+    }
+
+    /// World matrices of every node that has a mesh attachment for `model`.
+    pub fn mesh_instances(&self, model: usize) -> impl Iterator<Item = InstanceRaw> {
+        self.iter()
+            .filter(move |(_, node)| node.attachment == Some(Attachment::Mesh { model }))
+            .map(|(_, node)| {
+                InstanceRaw::from_model_matrix(node.world_matrix())
+                    .with_previous_model_matrix(node.previous_world_matrix())
+            })
+    }
+
+    /// Writes the world transforms of all mesh nodes for `model` into its instance buffer.
+    pub fn write_instances(&self, model: usize, instance_buffer: &mut InstanceBuffer) {
+        instance_buffer.sync_raw(self.mesh_instances(model));
+    }
+
+    /// Writes the world positions of all light nodes into the light list.
+    pub fn write_lights(&self, light_manager: &mut LightManager) {
+        for (_, node) in self.iter() {
+            if let Some(Attachment::Light { light }) = node.attachment
+                && let Some(light) = light_manager.lights.get_mut(light)
+            {
+                light.position = node.world_position();
+            }
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use anyhow::*;
+
+/// Display P3 chromaticities (red, green, blue, white). The display view LUT
+/// in hdr.slang transforms into Display P3, which uses the sRGB transfer function.
+const DISPLAY_P3_CHROMATICITIES: [(f32, f32); 4] = [
+    (0.680, 0.320),
+    (0.265, 0.690),
+    (0.150, 0.060),
+    (0.3127, 0.3290),
+];
+/// Rec.709 chromaticities (red, green, blue, white), the primaries the scene is
+/// rendered in.
+const REC709_CHROMATICITIES: [(f32, f32); 4] = [
+    (0.640, 0.330),
+    (0.300, 0.600),
+    (0.150, 0.060),
+    (0.3127, 0.3290),
+];
+/// cICP chunk contents: Display P3 primaries (12), sRGB transfer (13), RGB (0), full range
+const DISPLAY_P3_CICP: [u8; 4] = [12, 13, 0, 1];
+
+/// A copy of a texture in a buffer that can be mapped for reading.
+struct Readback {
+    buffer: wgpu::Buffer,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    /// Set by the map_async callback once the buffer can be read
+    mapped: Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>,
+}
+
+impl Readback {
+    fn copy_from(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        label: &str,
+    ) -> Self {
+        let format = texture.format();
+        let bytes_per_pixel = format.block_copy_size(None).unwrap_or(4);
+        let padded_bytes_per_row = (texture.width() * bytes_per_pixel)
+            .next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (padded_bytes_per_row * texture.height()) as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(texture.height()),
+                },
+            },
+            texture.size(),
+        );
+
+        Self {
+            buffer,
+            format,
+            width: texture.width(),
+            height: texture.height(),
+            padded_bytes_per_row,
+            mapped: Default::default(),
+        }
+    }
+
+    fn map(&self) {
+        let mapped = self.mapped.clone();
+        self.buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                *mapped.lock().unwrap() = Some(result);
+            });
+    }
+
+    fn is_mapped(&self) -> bool {
+        self.mapped.lock().unwrap().is_some()
+    }
+
+    /// Reads the mapped buffer, without the padding at the end of every row.
+    fn read(&self) -> Result<Vec<u8>> {
+        if let Some(Err(err)) = self.mapped.lock().unwrap().as_ref() {
+            bail!("Failed to read back {:?}: {err}", self.format);
+        }
+
+        let row_size = (self.width * self.format.block_copy_size(None).unwrap_or(4)) as usize;
+        let data = {
+            let mapped = self.buffer.slice(..).get_mapped_range();
+            mapped
+                .chunks_exact(self.padded_bytes_per_row as usize)
+                .flat_map(|row| &row[..row_size])
+                .copied()
+                .collect()
+        };
+        self.buffer.unmap();
+
+        Ok(data)
+    }
+}
+
+/// Which files a screenshot is saved as.
+#[derive(Clone, Copy, Debug)]
+pub struct ScreenshotFormats {
+    /// The display-referred image that ended up on the screen
+    pub png: bool,
+    /// The scene-linear HDR render texture
+    pub exr: bool,
+}
+
+impl Default for ScreenshotFormats {
+    fn default() -> Self {
+        Self {
+            png: true,
+            exr: true,
+        }
+    }
+}
+
+/// Where the next frame is saved. The extension of each format is appended to the path.
+struct ScreenshotRequest {
+    path: PathBuf,
+    formats: ScreenshotFormats,
+}
+
+/// A frame that has been copied into readback buffers, waiting for the GPU.
+struct PendingScreenshot {
+    path: PathBuf,
+    /// What ended up on the screen, after tonemapping
+    display: Option<Readback>,
+    /// The scene-linear HDR render texture, exposed but not tonemapped
+    scene: Option<Readback>,
+    exposure_ev: f32,
+    mapping: bool,
+}
+
+impl PendingScreenshot {
+    fn is_mapped(&self) -> bool {
+        self.mapping
+            && self.display.as_ref().is_none_or(Readback::is_mapped)
+            && self.scene.as_ref().is_none_or(Readback::is_mapped)
+    }
+}
+
+/// Captures the current frame to disk, both as the display-referred PNG that
+/// ended up on the screen, and as a scene-linear EXR of the HDR render texture.
+///
+/// Frames are copied into buffers that are only read once the GPU is done with
+/// them, and the files are encoded on a separate thread, so capturing doesn't
+/// stall the frame loop.
+#[derive(Default)]
+pub struct ScreenshotCapture {
+    requested: Option<ScreenshotRequest>,
+    pending: Vec<PendingScreenshot>,
+    writers: Vec<JoinHandle<Result<()>>>,
+}
+
+impl ScreenshotCapture {
+    /// Captures the next rendered frame to the working directory.
+    pub fn request(&mut self) {
+        if cfg!(target_arch = "wasm32") {
+            log::warn!("Screenshots can only be saved on native targets.");
+            return;
+        }
+
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.request_to(
+            format!("screenshot-{timestamp}").into(),
+            ScreenshotFormats::default(),
+        );
+    }
+
+    /// Captures the next rendered frame to `path`, with the extension of each
+    /// format appended.
+    pub fn request_to(&mut self, path: PathBuf, formats: ScreenshotFormats) {
+        self.requested = Some(ScreenshotRequest { path, formats });
+    }
+
+    /// Copies the frame into readback buffers if a screenshot was requested.
+    /// Needs to be called after the frame has been drawn to the surface, but
+    /// before it is submitted.
+    pub fn record(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        surface_texture: &wgpu::Texture,
+        hdr_texture: &wgpu::Texture,
+        exposure_ev: f32,
+    ) {
+        let Some(request) = self.requested.take() else {
+            return;
+        };
+
+        let display = if !request.formats.png {
+            None
+        } else if !surface_texture
+            .usage()
+            .contains(wgpu::TextureUsages::COPY_SRC)
+        {
+            log::warn!("The surface can't be copied from, only saving the HDR image.");
+            None
+        } else if !matches!(
+            surface_texture.format().remove_srgb_suffix(),
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Bgra8Unorm
+        ) {
+            log::warn!(
+                "Can't save surface format {:?} as PNG, only saving the HDR image.",
+                surface_texture.format()
+            );
+            None
+        } else {
+            Some(Readback::copy_from(
+                device,
+                encoder,
+                surface_texture,
+                "Screenshot Display Readback Buffer",
+            ))
+        };
+        let scene = request.formats.exr.then(|| {
+            Readback::copy_from(
+                device,
+                encoder,
+                hdr_texture,
+                "Screenshot Scene Readback Buffer",
+            )
+        });
+
+        self.pending.push(PendingScreenshot {
+            path: request.path,
+            display,
+            scene,
+            exposure_ev,
+            mapping: false,
+        });
+    }
+
+    /// Starts reading back the screenshots recorded this frame. Needs to be
+    /// called after the frame has been submitted.
+    pub fn submitted(&mut self) {
+        for screenshot in self.pending.iter_mut().filter(|s| !s.mapping) {
+            if let Some(display) = &screenshot.display {
+                display.map();
+            }
+            if let Some(scene) = &screenshot.scene {
+                scene.map();
+            }
+            screenshot.mapping = true;
+        }
+    }
+
+    /// Saves the screenshots that have been read back. This never waits for
+    /// the GPU, screenshots that aren't ready yet are checked again next frame.
+    pub fn poll(&mut self, device: &wgpu::Device) {
+        if self.pending.is_empty() && self.writers.is_empty() {
+            return;
+        }
+        if let Err(err) = device.poll(wgpu::PollType::Poll) {
+            log::warn!("Failed to poll the device for screenshots: {err}");
+        }
+        self.save_mapped();
+
+        let (finished, running) = std::mem::take(&mut self.writers)
+            .into_iter()
+            .partition::<Vec<_>, _>(JoinHandle::is_finished);
+        self.writers = running;
+        for writer in finished {
+            if let Err(err) = join(writer) {
+                log::error!("{err}");
+            }
+        }
+    }
+
+    /// Blocks until at most `max_in_flight` screenshots are still being read
+    /// back or written, and returns the first error of the ones that finished.
+    /// All screenshots need to have been submitted.
+    pub fn wait(&mut self, device: &wgpu::Device, max_in_flight: usize) -> Result<()> {
+        while self.pending.len() + self.writers.len() > max_in_flight {
+            if self.pending.is_empty() {
+                join(self.writers.remove(0))?;
+            } else {
+                device.poll(wgpu::PollType::Wait)?;
+                self.save_mapped();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts writing the screenshots whose buffers can be read.
+    fn save_mapped(&mut self) {
+        let (ready, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(PendingScreenshot::is_mapped);
+        self.pending = pending;
+
+        for screenshot in ready {
+            match save(screenshot) {
+                Result::Ok(writer) => self.writers.push(writer),
+                Err(err) => log::error!("Failed to save screenshot: {err}"),
+            }
+        }
+    }
+}
+
+/// Reads the buffers of a screenshot and writes its files on a separate thread.
+fn save(screenshot: PendingScreenshot) -> Result<JoinHandle<Result<()>>> {
+    let display = match &screenshot.display {
+        Some(readback) => Some((
+            readback.read()?,
+            readback.format,
+            readback.width,
+            readback.height,
+        )),
+        None => None,
+    };
+    let scene = match &screenshot.scene {
+        Some(readback) => Some((readback.read()?, readback.width, readback.height)),
+        None => None,
+    };
+    let path = screenshot.path;
+    let exposure_ev = screenshot.exposure_ev;
+
+    Ok(std::thread::spawn(move || {
+        if let Some((pixels, format, width, height)) = display {
+            let path = path.with_added_extension("png");
+            write_png(&path, &pixels, format, width, height)
+                .with_context(|| format!("Failed to save screenshot {}", path.display()))?;
+            log::info!("Saved screenshot {}", path.display());
+        }
+
+        if let Some((pixels, width, height)) = scene {
+            let path = path.with_added_extension("exr");
+            write_exr(&path, &pixels, width, height, exposure_ev)
+                .with_context(|| format!("Failed to save screenshot {}", path.display()))?;
+            log::info!("Saved scene-linear screenshot {}", path.display());
+        }
+
+        Ok(())
+    }))
+}
+
+/// Waits for a writer thread to finish.
+fn join(writer: JoinHandle<Result<()>>) -> Result<()> {
+    writer
+        .join()
+        .unwrap_or_else(|_| Err(anyhow!("The screenshot writer thread panicked.")))
+}
+
+/// Writes the surface image as an 8 bit PNG, tagged as Display P3. The cICP
+/// chunk describes the color space exactly, the gAMA and cHRM chunks are for
+/// viewers that don't support it yet.
+fn write_png(
+    path: &Path,
+    pixels: &[u8],
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+) -> Result<()> {
+    let is_bgra = format.remove_srgb_suffix() == wgpu::TextureFormat::Bgra8Unorm;
+    // The alpha of the surface is meaningless, the window is always opaque
+    let rgb = pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            if is_bgra {
+                [pixel[2], pixel[1], pixel[0]]
+            } else {
+                [pixel[0], pixel[1], pixel[2]]
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_gamma(png::ScaledFloat::new(1.0 / 2.2));
+    let [red, green, blue, white] = DISPLAY_P3_CHROMATICITIES;
+    encoder.set_source_chromaticities(png::SourceChromaticities::new(white, red, green, blue));
+
+    let mut writer = encoder.write_header()?;
+    writer.write_chunk(png::chunk::cICP, &DISPLAY_P3_CICP)?;
+    writer.write_image_data(&rgb)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Writes the Rgba16Float HDR render texture as a half float EXR. The pixels
+/// are already exposed, and the EV100 of the camera is stored as the
+/// `exposureEv` attribute.
+fn write_exr(path: &Path, pixels: &[u8], width: u32, height: u32, exposure_ev: f32) -> Result<()> {
+    use exr::prelude::*;
+
+    // The mapped buffer isn't guaranteed to be aligned for u16
+    let texels: Vec<u16> = pixels
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+    let channels = SpecificChannels::rgba(|position: Vec2<usize>| {
+        let index = (position.y() * width as usize + position.x()) * 4;
+        let texel = &texels[index..index + 4];
+        (
+            f16::from_bits(texel[0]),
+            f16::from_bits(texel[1]),
+            f16::from_bits(texel[2]),
+            f16::from_bits(texel[3]),
+        )
+    });
+
+    let mut image = Image::from_channels((width as usize, height as usize), channels);
+    let [red, green, blue, white] = REC709_CHROMATICITIES.map(|(x, y)| Vec2(x, y));
+    image.attributes.chromaticities = Some(attribute::Chromaticities {
+        red,
+        green,
+        blue,
+        white,
+    });
+    image.attributes.other.insert(
+        Text::from("exposureEv"),
+        attribute::AttributeValue::F32(exposure_ev),
+    );
+
+    image.write().to_file(path)?;
+
+    Ok(())
+}
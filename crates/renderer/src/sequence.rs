@@ -0,0 +1,264 @@
+use std::path::Path;
+
+use anyhow::*;
+use cgmath::{Deg, Point3, prelude::*};
+
+use crate::{
+    RenderPath, State,
+    camera::{CameraProperties, OrbitCameraController},
+    screenshot::ScreenshotFormats,
+};
+
+/// Frames that may be read back or written to disk at the same time. This
+/// bounds the memory that is used when encoding the files can't keep up with
+/// rendering them.
+const MAX_FRAMES_IN_FLIGHT: usize = 8;
+
+/// Orbits the camera around a target over the course of the sequence, with the
+/// parameters of the interactive orbit camera.
+#[derive(Clone, Copy, Debug)]
+pub struct Turntable {
+    pub target: Point3<f32>,
+    pub radius: f32,
+    pub pitch: Deg<f32>,
+    /// Yaw of the first frame
+    pub start_yaw: Deg<f32>,
+    /// Full turns over the whole sequence. With whole turns the last frame
+    /// leads back into the first one, so the sequence loops.
+    pub revolutions: f32,
+}
+
+impl Default for Turntable {
+    /// The same view that the interactive camera starts with.
+    fn default() -> Self {
+        Self {
+            target: Point3::origin(),
+            radius: 4.0,
+            pitch: Deg(-32.0),
+            start_yaw: Deg(0.0),
+            revolutions: 1.0,
+        }
+    }
+}
+
+impl Turntable {
+    fn apply(&self, frame: u32, frame_count: u32, camera: &mut CameraProperties) {
+        let turns = self.revolutions * frame as f32 / frame_count as f32;
+        let yaw = self.start_yaw + Deg(360.0) * turns;
+
+        OrbitCameraController::new(self.target, 0.0, 0.0, self.radius, yaw, self.pitch)
+            .update_camera(camera);
+    }
+}
+
+/// Pose of the camera at a point in time of a [`CameraPath`].
+#[derive(Clone, Copy, Debug)]
+pub struct CameraKeyframe {
+    /// In seconds
+    pub time: f32,
+    pub position: Point3<f32>,
+    pub yaw: Deg<f32>,
+    pub pitch: Deg<f32>,
+}
+
+/// Keyframed camera poses. Between two keyframes the position, yaw and pitch
+/// are interpolated linearly, with the yaw taking the shorter way around.
+/// Before the first and after the last keyframe the camera holds still, and
+/// two keyframes at the same time make a cut.
+#[derive(Clone, Debug)]
+pub struct CameraPath {
+    /// Sorted by time
+    keyframes: Vec<CameraKeyframe>,
+}
+
+impl CameraPath {
+    pub fn new(mut keyframes: Vec<CameraKeyframe>) -> Result<Self> {
+        ensure!(
+            !keyframes.is_empty(),
+            "A camera path needs at least one keyframe."
+        );
+        // The sort is stable, so keyframes of a cut stay in order
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        Ok(Self { keyframes })
+    }
+
+    /// Parses a camera path with one keyframe per line, written as
+    /// `time x y z yaw pitch` with the time in seconds and the angles in
+    /// degrees. Empty lines and everything after a `#` are ignored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let keyframes = text
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                (!line.is_empty()).then_some((index + 1, line))
+            })
+            .map(|(line_number, line)| {
+                let values = line
+                    .split_whitespace()
+                    .map(str::parse::<f32>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("Invalid number on line {line_number}."))?;
+                let &[time, x, y, z, yaw, pitch] = values.as_slice() else {
+                    bail!(
+                        "Expected `time x y z yaw pitch` on line {line_number}, but found {} values.",
+                        values.len()
+                    );
+                };
+
+                Ok(CameraKeyframe {
+                    time,
+                    position: Point3::new(x, y, z),
+                    yaw: Deg(yaw),
+                    pitch: Deg(pitch),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::new(keyframes)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read camera path {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid camera path {}", path.display()))
+    }
+
+    /// Time of the last keyframe, in seconds.
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// Whether the camera cuts after `start` and up to `end`.
+    fn cuts_between(&self, start: f32, end: f32) -> bool {
+        self.keyframes.windows(2).any(|pair| {
+            let time = pair[0].time;
+            time == pair[1].time && time > start && time <= end
+        })
+    }
+
+    fn apply(&self, time: f32, camera: &mut CameraProperties) {
+        let next = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.time <= time);
+        let pose = match (
+            self.keyframes.get(next.wrapping_sub(1)),
+            self.keyframes.get(next),
+        ) {
+            (Some(previous), Some(next)) => {
+                let t = (time - previous.time) / (next.time - previous.time);
+                CameraKeyframe {
+                    time,
+                    position: previous.position + (next.position - previous.position) * t,
+                    yaw: previous.yaw + (next.yaw - previous.yaw).normalize_signed() * t,
+                    pitch: previous.pitch + (next.pitch - previous.pitch) * t,
+                }
+            }
+            (Some(keyframe), None) | (None, Some(keyframe)) => *keyframe,
+            (None, None) => return,
+        };
+
+        camera.position = pose.position;
+        camera.yaw = pose.yaw.into();
+        camera.pitch = pose.pitch.into();
+    }
+}
+
+/// How the camera moves over the course of a sequence.
+#[derive(Clone, Debug)]
+pub enum CameraAnimation {
+    Turntable(Turntable),
+    Path(CameraPath),
+}
+
+/// A fixed number of frames rendered at a fixed resolution. Every frame
+/// advances the time by exactly `1 / frames_per_second`, no matter how long it
+/// took to render, and the scene animations advance by one step per frame.
+#[derive(Clone, Debug)]
+pub struct Sequence {
+    pub width: u32,
+    pub height: u32,
+    pub frame_count: u32,
+    pub frames_per_second: f32,
+    pub camera: CameraAnimation,
+    pub formats: ScreenshotFormats,
+    pub render_path: RenderPath,
+}
+
+impl Sequence {
+    /// Renders every frame of the sequence with `model` from `res/`, without a
+    /// window. The frames are written to `output_dir` as `frame-0000.png` and
+    /// `frame-0000.exr`, numbered from zero.
+    pub async fn render(&self, model: &str, output_dir: &Path) -> Result<()> {
+        ensure!(self.frame_count > 0, "A sequence needs at least one frame.");
+        std::fs::create_dir_all(output_dir)?;
+
+        let mut state = State::new_headless(self.width, self.height, model).await?;
+        state.render_path = self.render_path;
+        state.sky_pipeline.finish_streaming(
+            &state.device,
+            &state.queue,
+            state.asset_server.sampler_cache(),
+        );
+
+        let target = state.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Sequence Frame Texture"),
+            size: wgpu::Extent3d {
+                width: state.config.width,
+                height: state.config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: state.config.format,
+            usage: state.config.usage,
+            view_formats: &[],
+        });
+
+        for frame in 0..self.frame_count {
+            match &self.camera {
+                CameraAnimation::Turntable(turntable) => {
+                    turntable.apply(frame, self.frame_count, &mut state.camera.properties)
+                }
+                CameraAnimation::Path(path) => {
+                    let time = frame as f32 / self.frames_per_second;
+                    let previous_time = (frame as f32 - 1.0) / self.frames_per_second;
+                    // The frames before a cut don't help with the ones after it
+                    if path.cuts_between(previous_time, time) {
+                        state.taa_pipeline.reset_history();
+                    }
+                    path.apply(time, &mut state.camera.properties);
+                }
+            }
+            state.update_frame(1.0 / self.frames_per_second);
+
+            state
+                .screenshots
+                .request_to(output_dir.join(format!("frame-{frame:04}")), self.formats);
+            state.render_to(&target);
+            state
+                .screenshots
+                .wait(&state.device, MAX_FRAMES_IN_FLIGHT)?;
+        }
+
+        state.screenshots.wait(&state.device, 0)
+    }
+}
+
+/// File names of all OBJ models in `res/`, sorted by name.
+pub fn list_models() -> Result<Vec<String>> {
+    let res_dir = Path::new(env!("OUT_DIR")).join("res");
+    let mut models = std::fs::read_dir(&res_dir)
+        .with_context(|| format!("Failed to list {}", res_dir.display()))?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let is_obj = path.extension().is_some_and(|extension| extension == "obj");
+            is_obj.then(|| path.file_name()?.to_str().map(str::to_owned))?
+        })
+        .collect::<Vec<_>>();
+    models.sort();
+
+    Ok(models)
+}
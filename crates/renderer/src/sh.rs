@@ -0,0 +1,198 @@
+//! Helpers for the SH coefficients written by `sh-coefficient-baker`.
+//!
+//! The baker stores irradiance coefficients for its own non-normalized basis (the
+//! associated Legendre polynomials with the Condon-Shortley phase), with the
+//! normalization and cosine convolution already folded into them. This keeps the
+//! evaluation in the shader cheap, but it means the coefficients have to be
+//! converted to the standard real SH basis before they can be rotated.
+use cgmath::Matrix3;
+
+use crate::sky::ShCoefficients;
+
+/// Highest number of SH bands the sky shader can evaluate.
+/// This needs to match MAX_SH_BANDS in sky.slang
+pub const MAX_SH_BANDS: usize = 5;
+pub const MAX_SH_COEFFICIENTS: usize = MAX_SH_BANDS * MAX_SH_BANDS;
+
+/// Number of bands for the given number of coefficients, if it is a valid count.
+pub fn band_count(coefficient_count: usize) -> Option<usize> {
+    let bands = coefficient_count.isqrt();
+    (bands > 0 && bands * bands == coefficient_count).then_some(bands)
+}
+
+fn sh_index(m: isize, l: usize) -> usize {
+    (l as isize * (l as isize + 1) + m) as usize
+}
+
+/// returns n! / d!
+fn factorial_division(n: usize, d: usize) -> f64 {
+    let (low, high, invert) = if n >= d { (d, n, false) } else { (n, d, true) };
+    let result = ((low + 1)..=high).map(|i| i as f64).product::<f64>();
+    if invert { 1.0 / result } else { result }
+}
+
+/// Factor between the baker's basis and the standard real SH basis, including
+/// the Condon-Shortley phase: `Y_lm = factor * P_lm * (cos or sin of m phi)`.
+fn basis_factor(m: isize, l: usize) -> f64 {
+    let m_abs = m.unsigned_abs();
+    let k = ((2 * l + 1) as f64 / (4.0 * std::f64::consts::PI)
+        * factorial_division(l - m_abs, l + m_abs))
+    .sqrt();
+    let normalization = if m == 0 {
+        k
+    } else {
+        std::f64::consts::SQRT_2 * k
+    };
+    let phase = if m_abs % 2 == 1 { -1.0 } else { 1.0 };
+
+    normalization * phase
+}
+
+/// SH rotation matrices per band, using the recurrence from
+/// "Rotation Matrices for Real Spherical Harmonics. Direct Determination by Recursion"
+/// by Ivanic and Ruedenberg, with the corrections from their 1998 erratum.
+struct ShRotation {
+    /// Row major (2l+1)x(2l+1) matrix for every band l
+    bands: Vec<Vec<f64>>,
+}
+
+impl ShRotation {
+    fn new(band_count: usize, rotation: Matrix3<f32>) -> Self {
+        let r = |row: usize, col: usize| rotation[col][row] as f64;
+
+        let mut bands = vec![vec![1.0]];
+        if band_count > 1 {
+            // The real SH of band 1 are proportional to (y, z, x)
+            bands.push(vec![
+                r(1, 1),
+                r(1, 2),
+                r(1, 0),
+                r(2, 1),
+                r(2, 2),
+                r(2, 0),
+                r(0, 1),
+                r(0, 2),
+                r(0, 0),
+            ]);
+        }
+
+        let mut sh_rotation = Self { bands };
+        for l in 2..band_count {
+            let size = 2 * l + 1;
+            let mut band = vec![0.0; size * size];
+            for m in -(l as isize)..=(l as isize) {
+                for n in -(l as isize)..=(l as isize) {
+                    band[(m + l as isize) as usize * size + (n + l as isize) as usize] =
+                        sh_rotation.element(l, m, n);
+                }
+            }
+            sh_rotation.bands.push(band);
+        }
+
+        sh_rotation
+    }
+
+    fn get(&self, l: usize, m: isize, n: isize) -> f64 {
+        let size = 2 * l + 1;
+        self.bands[l][(m + l as isize) as usize * size + (n + l as isize) as usize]
+    }
+
+    fn p(&self, i: isize, a: isize, b: isize, l: usize) -> f64 {
+        let l_i = l as isize;
+        if b == l_i {
+            self.get(1, i, 1) * self.get(l - 1, a, l_i - 1)
+                - self.get(1, i, -1) * self.get(l - 1, a, -l_i + 1)
+        } else if b == -l_i {
+            self.get(1, i, 1) * self.get(l - 1, a, -l_i + 1)
+                + self.get(1, i, -1) * self.get(l - 1, a, l_i - 1)
+        } else {
+            self.get(1, i, 0) * self.get(l - 1, a, b)
+        }
+    }
+
+    fn u(&self, m: isize, n: isize, l: usize) -> f64 {
+        self.p(0, m, n, l)
+    }
+
+    fn v(&self, m: isize, n: isize, l: usize) -> f64 {
+        if m == 0 {
+            self.p(1, 1, n, l) + self.p(-1, -1, n, l)
+        } else if m > 0 {
+            let d: f64 = if m == 1 { 1.0 } else { 0.0 };
+            self.p(1, m - 1, n, l) * (1.0 + d).sqrt() - self.p(-1, -m + 1, n, l) * (1.0 - d)
+        } else {
+            let d: f64 = if m == -1 { 1.0 } else { 0.0 };
+            self.p(1, m + 1, n, l) * (1.0 - d) + self.p(-1, -m - 1, n, l) * (1.0 + d).sqrt()
+        }
+    }
+
+    fn w(&self, m: isize, n: isize, l: usize) -> f64 {
+        if m > 0 {
+            self.p(1, m + 1, n, l) + self.p(-1, -m - 1, n, l)
+        } else {
+            self.p(1, m - 1, n, l) - self.p(-1, -m + 1, n, l)
+        }
+    }
+
+    fn element(&self, l: usize, m: isize, n: isize) -> f64 {
+        let l_f = l as f64;
+        let m_abs = m.abs() as f64;
+        let d = if m == 0 { 1.0 } else { 0.0 };
+        let denominator = if n.unsigned_abs() == l {
+            2.0 * l_f * (2.0 * l_f - 1.0)
+        } else {
+            (l_f + n as f64) * (l_f - n as f64)
+        };
+
+        let u = ((l_f + m as f64) * (l_f - m as f64) / denominator).sqrt();
+        let v = 0.5
+            * ((1.0 + d) * (l_f + m_abs - 1.0) * (l_f + m_abs) / denominator).sqrt()
+            * (1.0 - 2.0 * d);
+        let w = -0.5 * ((l_f - m_abs - 1.0) * (l_f - m_abs) / denominator).sqrt() * (1.0 - d);
+
+        // The terms with a zero factor would read outside of the previous band
+        let mut result = 0.0;
+        if u != 0.0 {
+            result += u * self.u(m, n, l);
+        }
+        if v != 0.0 {
+            result += v * self.v(m, n, l);
+        }
+        if w != 0.0 {
+            result += w * self.w(m, n, l);
+        }
+        result
+    }
+}
+
+/// Rotates SH coefficients as if the environment they were computed from had
+/// been rotated by `rotation`.
+pub fn rotate_sh_coefficients(
+    coefficients: &ShCoefficients,
+    rotation: Matrix3<f32>,
+) -> ShCoefficients {
+    let Some(bands) = band_count(coefficients.len()) else {
+        return coefficients.clone();
+    };
+    let sh_rotation = ShRotation::new(bands, rotation);
+
+    let mut result = vec![[0.0; 3]; coefficients.len()];
+    for l in 0..bands {
+        let l_i = l as isize;
+        for m in -l_i..=l_i {
+            let mut rotated = [0.0f64; 3];
+            for n in -l_i..=l_i {
+                let factor = sh_rotation.get(l, m, n) / basis_factor(n, l);
+                let coefficient = coefficients[sh_index(n, l)];
+                for channel in 0..3 {
+                    rotated[channel] += factor * coefficient[channel] as f64;
+                }
+            }
+
+            let factor = basis_factor(m, l);
+            result[sh_index(m, l)] = rotated.map(|value| (value * factor) as f32);
+        }
+    }
+
+    result
+}
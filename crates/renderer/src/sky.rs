@@ -1,28 +1,160 @@
+use anyhow::Context;
+use cgmath::{Deg, Matrix3, Rad};
 use wgpu::{RenderPass, util::DeviceExt};
 
 use crate::{
-    create_render_pipeline, hdr, resources, texture, wgpu_include_slang_shader,
+    camera::DepthMode,
+    create_render_pipeline,
+    environment::EnvironmentBaker,
+    procedural_sky::{PreethamSky, PreethamUniform},
+    resources,
+    sampler_cache::SamplerCache,
+    sh, texture,
+    texture_streaming::StreamingTexture,
+    wgpu_include_slang_shader,
     wgpu_traits::AsBindGroup,
 };
 
 pub type ShCoefficients = Vec<[f32; 3]>;
-pub type UniformShCoefficients = [[f32; 4]; 9];
+pub type UniformShCoefficients = [[f32; 4]; sh::MAX_SH_COEFFICIENTS];
 
 fn uniformify_sh_coefficients(coeffs: &ShCoefficients) -> UniformShCoefficients {
-    let mut result: UniformShCoefficients = [[0.0; 4]; 9];
+    let mut result: UniformShCoefficients = [[0.0; 4]; sh::MAX_SH_COEFFICIENTS];
 
-    for (idx, val) in coeffs.iter().enumerate() {
+    for (idx, val) in coeffs.iter().take(sh::MAX_SH_COEFFICIENTS).enumerate() {
         result[idx] = [val[0], val[1], val[2], 0.0];
     }
 
     result
 }
 
+/// Makes sure the coefficients form complete bands, and drops the bands that
+/// the shader can't evaluate.
+fn validate_sh_coefficients(mut coeffs: ShCoefficients) -> anyhow::Result<ShCoefficients> {
+    let Some(band_count) = sh::band_count(coeffs.len()) else {
+        anyhow::bail!(
+            "Expected a square number of SH coefficients, but got {}.",
+            coeffs.len()
+        );
+    };
+
+    if band_count > sh::MAX_SH_BANDS {
+        log::warn!(
+            "SH coefficients have {band_count} bands but only {} are supported. The rest will be ignored.",
+            sh::MAX_SH_BANDS
+        );
+        coeffs.truncate(sh::MAX_SH_COEFFICIENTS);
+    }
+
+    Ok(coeffs)
+}
+
+/// Where the environment of the sky comes from.
+pub enum EnvironmentSource<'a> {
+    /// A prefiltered cubemap baked by `cubemap-ktx2-baker`, next to the `<path>.bin`
+    /// SH coefficients file written by `sh-coefficient-baker`. Its mips are
+    /// streamed in over the first frames.
+    Baked(&'a str),
+    /// A raw equirectangular HDR image (EXR or HDR) that gets prefiltered on the
+    /// GPU while loading. This needs compute shaders, so it is not available on WebGL.
+    #[allow(unused)]
+    Equirectangular(&'a str),
+}
+
+impl EnvironmentSource<'_> {
+    async fn load(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+    ) -> anyhow::Result<(StreamingTexture, ShCoefficients)> {
+        match *self {
+            EnvironmentSource::Baked(path) => {
+                let texture = resources::load_streaming_texture(
+                    path,
+                    device,
+                    queue,
+                    sampler_cache,
+                    Default::default(),
+                )
+                .await
+                .context("Failed to load sky texture.")?;
+
+                let sh_path = format!("{path}.bin");
+                let sh_coefficients = resources::load_sh_coefficients(&sh_path)
+                    .await
+                    .context("Failed to load SH coefficients file for sky texture.")?;
+
+                Ok((texture, sh_coefficients))
+            }
+            EnvironmentSource::Equirectangular(path) => {
+                let image = resources::load_hdr_image(path)
+                    .await
+                    .context("Failed to load sky image.")?;
+
+                let (texture, sh_coefficients) = EnvironmentBaker::new(device, sampler_cache)?
+                    .bake_equirect(device, queue, &image, Some(path))?;
+
+                Ok((StreamingTexture::resident(texture), sh_coefficients))
+            }
+        }
+    }
+}
+
+/// DFG LUT baked by `dfg-lut-baker`. When it can't be loaded, the analytical
+/// approximation in sky.slang is used instead.
+const DFG_LUT_PATH: &str = "dfg-lut.ktx2";
+
+/// Stand-in for the DFG LUT so that the bind group is always complete.
+/// It is never sampled, since `use_dfg_lut` stays off.
+fn create_placeholder_dfg_lut(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    sampler_cache: &SamplerCache,
+) -> texture::Texture {
+    let texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Placeholder DFG LUT"),
+            size: wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rg16Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&[half::f16::ONE.to_bits(); 2]),
+    );
+    let view = texture.create_view(&Default::default());
+    let sampler = sampler_cache.get(device, &Default::default());
+
+    texture::Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
 pub struct SkyPipeline {
     pipeline: wgpu::RenderPipeline,
     bind_group_layout: wgpu::BindGroupLayout,
     bind_group: Option<wgpu::BindGroup>,
-    sky_texture: texture::Texture,
+    sky_texture: StreamingTexture,
+    /// SH coefficients of `sky_texture`, kept around to restore them when the
+    /// procedural sky gets turned off.
+    sky_sh_coefficients: ShCoefficients,
+    /// Environment generated from the procedural sky, used instead of
+    /// `sky_texture` while it is enabled.
+    procedural_texture: Option<texture::Texture>,
+    /// Created on first use, since most runs never need it.
+    environment_baker: Option<EnvironmentBaker>,
+    dfg_lut_texture: texture::Texture,
     sky_uniform: SkyUniform,
     pub properties: SkyProperties,
     uniform_buffer: Option<wgpu::Buffer>,
@@ -32,26 +164,46 @@ impl SkyPipeline {
     pub async fn new(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
         camera_bind_group_layout: &wgpu::BindGroupLayout,
-    ) -> Self {
-        // let sky_path = "rogland_clear_night_cube.ktx2";
-        // let sky_path = "monkstown_castle.ktx2";
-        let sky_path = "large-corridor.ktx2";
-        // let sky_path = "debug-sky-faces.ktx2";
-        // let sky_path = "debug-sky-green-dot.ktx2";
-        let sky_texture = resources::load_texture(sky_path, device, queue, Default::default())
-            .await
-            .expect("Failed to load sky texture.");
-        let mip_count = sky_texture.texture.mip_level_count();
-
-        let sh_path = format!("{sky_path}.bin");
-        let sky_sh_coefficients = resources::load_sh_coefficients(&sh_path)
+        depth_mode: DepthMode,
+        source: EnvironmentSource<'_>,
+    ) -> anyhow::Result<Self> {
+        let (sky_texture, sky_sh_coefficients) = source
+            .load(device, queue, sampler_cache)
             .await
-            .expect("Failed to load SH coefficients file for sky texture.");
+            .context("Failed to load sky environment")?;
+        let mip_count = sky_texture.texture().texture.mip_level_count();
+        let sky_sh_coefficients = validate_sh_coefficients(sky_sh_coefficients)
+            .context("Invalid SH coefficients for sky texture")?;
+
+        let (dfg_lut_texture, has_dfg_lut) = match resources::load_texture(
+            DFG_LUT_PATH,
+            device,
+            queue,
+            sampler_cache,
+            None,
+            Default::default(),
+        )
+        .await
+        {
+            Ok(texture) => (texture, true),
+            Err(err) => {
+                log::warn!(
+                    "Failed to load DFG LUT, falling back to the analytical approximation: {err}"
+                );
+                (
+                    create_placeholder_dfg_lut(device, queue, sampler_cache),
+                    false,
+                )
+            }
+        };
 
         let properties = SkyProperties {
-            sh_coefficients: sky_sh_coefficients,
+            sh_coefficients: sky_sh_coefficients.clone(),
             mip_count,
+            has_dfg_lut,
+            use_dfg_lut: has_dfg_lut,
             ..Default::default()
         };
 
@@ -71,11 +223,13 @@ impl SkyPipeline {
             create_render_pipeline(
                 device,
                 &layout,
-                hdr::HDR_BUFFER_FORMAT,
-                Some(texture::Texture::DEPTH_FORMAT),
+                &crate::LIT_PASS_FORMATS,
+                // The sky is drawn on the far plane, behind everything else
+                Some(depth_mode.depth_stencil_state()),
                 &[],
                 wgpu::PrimitiveTopology::TriangleList,
                 shader,
+                "fs_main",
             )
         };
 
@@ -84,6 +238,10 @@ impl SkyPipeline {
             bind_group_layout: sky_bind_group_layout,
             bind_group: None,
             sky_texture,
+            sky_sh_coefficients,
+            procedural_texture: None,
+            environment_baker: None,
+            dfg_lut_texture,
             uniform_buffer: None,
             properties,
             sky_uniform,
@@ -91,7 +249,7 @@ impl SkyPipeline {
 
         sky_pipeline.init_all(device);
 
-        sky_pipeline
+        Ok(sky_pipeline)
     }
 
     pub fn draw_in_render_pass(
@@ -105,6 +263,82 @@ impl SkyPipeline {
         render_pass.draw(0..3, 0..1);
     }
 
+    /// Switches between the procedural sky and the loaded environment. The
+    /// procedural environment is generated on the GPU, so this needs to be
+    /// called again whenever the settings of the sky change.
+    pub fn set_procedural_sky(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+        sky: Option<PreethamSky>,
+    ) -> anyhow::Result<()> {
+        match sky {
+            Some(sky) => {
+                if self.environment_baker.is_none() {
+                    self.environment_baker = Some(EnvironmentBaker::new(device, sampler_cache)?);
+                }
+                let (texture, sh_coefficients) = self
+                    .environment_baker
+                    .as_ref()
+                    .unwrap()
+                    .bake_procedural(device, queue, sky.to_uniform(), Some("Procedural Sky"))?;
+
+                self.properties.mip_count = texture.texture.mip_level_count();
+                self.properties.sh_coefficients = sh_coefficients;
+                self.procedural_texture = Some(texture);
+            }
+            None => {
+                self.properties.mip_count = self.sky_texture.texture().texture.mip_level_count();
+                self.properties.sh_coefficients = self.sky_sh_coefficients.clone();
+                self.procedural_texture = None;
+            }
+        }
+        self.properties.procedural = sky;
+
+        self.init_bind_group(device);
+        self.queue_write_binding_resources(queue);
+
+        Ok(())
+    }
+
+    /// Uploads the next mip of the environment while it is still streaming in.
+    /// Needs to be called once per frame.
+    pub fn stream_textures(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+    ) {
+        if self.sky_texture.stream(device, queue, sampler_cache) {
+            self.init_bind_group(device);
+        }
+    }
+
+    /// Uploads all levels of the sky that are still missing at once, for when
+    /// the frames can't be blurry while it streams in.
+    pub fn finish_streaming(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+    ) {
+        let mut changed = false;
+        while self.sky_texture.stream(device, queue, sampler_cache) {
+            changed = true;
+        }
+        if changed {
+            self.init_bind_group(device);
+        }
+    }
+
+    /// Texture that is currently used as the environment.
+    fn environment_texture(&self) -> &texture::Texture {
+        self.procedural_texture
+            .as_ref()
+            .unwrap_or(self.sky_texture.texture())
+    }
+
     pub fn uniform_buffer(&self) -> &wgpu::Buffer {
         if self.uniform_buffer.is_none() {
             panic!("Uniform Buffer for HDR Pipeline has not been initialized!");
@@ -143,6 +377,22 @@ impl AsBindGroup for SkyPipeline {
                 ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                 count: None,
             },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
         ]
     }
 
@@ -157,11 +407,19 @@ impl AsBindGroup for SkyPipeline {
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&self.sky_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&self.environment_texture().view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::Sampler(&self.sky_texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&self.environment_texture().sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&self.dfg_lut_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&self.dfg_lut_texture.sampler),
                 },
             ],
         }))
@@ -207,29 +465,71 @@ impl AsBindGroup for SkyPipeline {
 pub struct SkyProperties {
     sh_coefficients: ShCoefficients,
     mip_count: u32,
-    pub exposure_ev: f32,
+    /// Rotation of the sky around the Y axis. This rotates the cubemap lookups
+    /// and the SH coefficients of the irradiance.
+    pub rotation: Deg<f32>,
+    /// Luminance in cd/m² of a texel value of 1 in the loaded environment.
+    /// HDRIs are rarely calibrated, so this brings them into the same units
+    /// as the lights. The procedural sky is already in cd/m².
+    pub intensity: f32,
     pub debug_sh_coefficients: bool,
+    has_dfg_lut: bool,
+    /// Use the baked DFG LUT instead of the analytical approximation, which also
+    /// enables the multiple scattering energy compensation. Ignored if the LUT
+    /// failed to load.
+    pub use_dfg_lut: bool,
+    /// Procedural sky that replaces the loaded environment, if any.
+    /// Set it with [`SkyPipeline::set_procedural_sky`].
+    procedural: Option<PreethamSky>,
+}
+
+impl SkyProperties {
+    pub fn procedural(&self) -> Option<&PreethamSky> {
+        self.procedural.as_ref()
+    }
 }
 
 impl Default for SkyProperties {
     fn default() -> Self {
         Self {
-            exposure_ev: -2.0,
+            intensity: 20.0,
             mip_count: 1,
             sh_coefficients: vec![[0.0; 3]; 9],
+            rotation: Deg(0.0),
             debug_sh_coefficients: false,
+            has_dfg_lut: false,
+            use_dfg_lut: false,
+            procedural: None,
         }
     }
 }
 
 impl From<&SkyProperties> for SkyUniform {
     fn from(value: &SkyProperties) -> Self {
+        let rotated_sh_coefficients = sh::rotate_sh_coefficients(
+            &value.sh_coefficients,
+            Matrix3::from_angle_y(value.rotation),
+        );
+        let rotation = Rad::from(value.rotation);
+
         Self {
-            sh_coefficients: uniformify_sh_coefficients(&value.sh_coefficients),
+            sh_coefficients: uniformify_sh_coefficients(&rotated_sh_coefficients),
             mip_count: value.mip_count as f32,
-            exposure_linear: f32::powf(2.0, value.exposure_ev),
+            intensity: if value.procedural.is_some() {
+                1.0
+            } else {
+                value.intensity
+            },
             debug_sh: value.debug_sh_coefficients as u8 as f32,
-            _padding: [0.0; 1],
+            use_dfg_lut: (value.has_dfg_lut && value.use_dfg_lut) as u8 as f32,
+            sh_band_count: sh::band_count(value.sh_coefficients.len()).unwrap_or(0) as u32,
+            rotation_cos: rotation.0.cos(),
+            rotation_sin: rotation.0.sin(),
+            procedural: value.procedural.is_some() as u8 as f32,
+            preetham: value
+                .procedural
+                .map(|sky| sky.to_uniform())
+                .unwrap_or_else(bytemuck::Zeroable::zeroed),
         }
     }
 }
@@ -238,8 +538,13 @@ impl From<&SkyProperties> for SkyUniform {
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct SkyUniform {
     pub sh_coefficients: UniformShCoefficients,
-    pub exposure_linear: f32,
+    pub intensity: f32,
     pub debug_sh: f32,
     pub mip_count: f32,
-    pub _padding: [f32; 1],
+    pub use_dfg_lut: f32,
+    pub sh_band_count: u32,
+    pub rotation_cos: f32,
+    pub rotation_sin: f32,
+    pub procedural: f32,
+    pub preetham: PreethamUniform,
 }
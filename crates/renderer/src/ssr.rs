@@ -0,0 +1,305 @@
+use std::sync::Arc;
+
+use crate::{
+    hdr::HDR_BUFFER_FORMAT,
+    post_process::{begin_pass, create_pipeline, sampler_entry, texture_entry, uniform_entry},
+    sampler_cache::SamplerCache,
+    texture, wgpu_include_slang_shader,
+};
+
+/// World normal of the lit surfaces, with the perceptual roughness in alpha
+pub const NORMAL_ROUGHNESS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// What the lit pass scales the reflected sky by, which can go above 1 with
+/// the energy compensation
+pub const SPECULAR_WEIGHT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+pub struct SsrProperties {
+    pub enabled: bool,
+    /// How far rays are marched, in world units
+    pub max_distance: f32,
+    /// How far behind the depth buffer a ray can be and still hit it, in
+    /// world units. Thin objects are missed with less, and rays hit what
+    /// they should pass behind with more.
+    pub thickness: f32,
+    pub step_count: u32,
+    /// Perceptual roughness above which surfaces only reflect the sky
+    pub max_roughness: f32,
+}
+
+impl Default for SsrProperties {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_distance: 8.0,
+            thickness: 0.25,
+            step_count: 48,
+            max_roughness: 0.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SsrUniform {
+    max_distance: f32,
+    thickness: f32,
+    step_count: u32,
+    max_roughness: f32,
+}
+
+impl From<&SsrProperties> for SsrUniform {
+    fn from(value: &SsrProperties) -> Self {
+        Self {
+            max_distance: value.max_distance,
+            thickness: value.thickness,
+            step_count: value.step_count,
+            max_roughness: value.max_roughness,
+        }
+    }
+}
+
+/// Everything that depends on the size of the render.
+struct SsrTargets {
+    normal_roughness: texture::Texture,
+    specular_weight: texture::Texture,
+    /// Full resolution result, copied back into the HDR render texture
+    output: texture::Texture,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Screen-space reflections. The IBL only reflects the sky, so objects never
+/// show up in each other's reflections. This marches the reflection of every
+/// pixel through the depth buffer, and where it hits something, replaces the
+/// reflected sky with the color of the render there.
+///
+/// The lit pass writes the normals, roughness and specular weights this needs
+/// next to its color.
+pub struct SsrPipeline {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: Arc<wgpu::Sampler>,
+    uniform_buffer: wgpu::Buffer,
+    targets: SsrTargets,
+    pub properties: SsrProperties,
+}
+
+impl SsrPipeline {
+    /// `color_view` and `depth_view` are the HDR render and depth buffer of the
+    /// lit pass. They need to be passed again to [`Self::resize`] whenever
+    /// they are recreated.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        sky_bind_group_layout: &wgpu::BindGroupLayout,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SSR Bind Group Layout"),
+            entries: &[
+                uniform_entry(0),
+                texture_entry(1, true),
+                texture_entry(2, false),
+                texture_entry(3, true),
+                texture_entry(4, true),
+                sampler_entry(5),
+            ],
+        });
+
+        // The sky is needed to take out what the lit pass reflected of it
+        let pipeline = create_pipeline(
+            device,
+            "SSR Pipeline",
+            &[
+                camera_bind_group_layout,
+                sky_bind_group_layout,
+                &bind_group_layout,
+            ],
+            wgpu_include_slang_shader!("ssr"),
+            &[Some(wgpu::ColorTargetState {
+                format: HDR_BUFFER_FORMAT,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        );
+
+        let sampler = sampler_cache.get(
+            device,
+            &wgpu::SamplerDescriptor {
+                label: Some("SSR Sampler"),
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            },
+        );
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSR Uniform Buffer"),
+            size: size_of::<SsrUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let targets = SsrTargets::new(
+            device,
+            sampler_cache,
+            &bind_group_layout,
+            &sampler,
+            &uniform_buffer,
+            color_view,
+            depth_view,
+            width,
+            height,
+        );
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buffer,
+            targets,
+            properties: SsrProperties::default(),
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) {
+        self.targets = SsrTargets::new(
+            device,
+            sampler_cache,
+            &self.bind_group_layout,
+            &self.sampler,
+            &self.uniform_buffer,
+            color_view,
+            depth_view,
+            width,
+            height,
+        );
+    }
+
+    /// Render target for the normals and roughness of the lit pass.
+    pub fn normal_roughness_view(&self) -> &wgpu::TextureView {
+        &self.targets.normal_roughness.view
+    }
+
+    /// Render target for the specular weights of the lit pass.
+    pub fn specular_weight_view(&self) -> &wgpu::TextureView {
+        &self.targets.specular_weight.view
+    }
+
+    pub fn queue_write_binding_resources(&self, queue: &wgpu::Queue) {
+        let uniform = SsrUniform::from(&self.properties);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Adds the reflections to `color_texture`. It needs to be the texture the
+    /// color view passed to [`Self::new`] or [`Self::resize`] belongs to.
+    pub fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        camera_bind_group: &wgpu::BindGroup,
+        sky_bind_group: &wgpu::BindGroup,
+        color_texture: &wgpu::Texture,
+    ) {
+        {
+            let mut pass = begin_pass(encoder, "SSR Pass", &[&self.targets.output]);
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, camera_bind_group, &[]);
+            pass.set_bind_group(1, sky_bind_group, &[]);
+            pass.set_bind_group(2, &self.targets.bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        // The pass reads the render, so it can't render into it directly
+        encoder.copy_texture_to_texture(
+            self.targets.output.texture.as_image_copy(),
+            color_texture.as_image_copy(),
+            color_texture.size(),
+        );
+    }
+}
+
+impl SsrTargets {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        uniform_buffer: &wgpu::Buffer,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let width = width.max(1);
+        let height = height.max(1);
+        let create_target = |format, usage, label| {
+            texture::Texture::create_2d_texture(
+                device,
+                sampler_cache,
+                width,
+                height,
+                format,
+                wgpu::TextureUsages::RENDER_ATTACHMENT | usage,
+                wgpu::FilterMode::Nearest,
+                Some(label),
+            )
+        };
+
+        let binding = wgpu::TextureUsages::TEXTURE_BINDING;
+        let normal_roughness =
+            create_target(NORMAL_ROUGHNESS_FORMAT, binding, "SSR Normal Roughness");
+        let specular_weight = create_target(SPECULAR_WEIGHT_FORMAT, binding, "SSR Specular Weight");
+        let output = create_target(
+            HDR_BUFFER_FORMAT,
+            wgpu::TextureUsages::COPY_SRC,
+            "SSR Output",
+        );
+
+        let view = |binding, view| wgpu::BindGroupEntry {
+            binding,
+            resource: wgpu::BindingResource::TextureView(view),
+        };
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("SSR Bind Group"),
+            layout: bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                view(1, color_view),
+                view(2, depth_view),
+                view(3, &normal_roughness.view),
+                view(4, &specular_weight.view),
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+        });
+
+        Self {
+            normal_roughness,
+            specular_weight,
+            output,
+            bind_group,
+        }
+    }
+}
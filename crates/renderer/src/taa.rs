@@ -0,0 +1,340 @@
+use std::sync::Arc;
+
+use cgmath::{Vector2, Zero};
+
+use crate::{
+    camera::DepthMode,
+    hdr::HDR_BUFFER_FORMAT,
+    post_process::{begin_pass, create_pipeline, sampler_entry, texture_entry, uniform_entry},
+    sampler_cache::SamplerCache,
+    texture, wgpu_include_slang_shader,
+};
+
+/// Format of the motion vectors, in UV units
+pub const VELOCITY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+/// Length of the Halton sequence the projection is jittered with. Every pixel
+/// is sampled at this many subpixel positions before they repeat.
+const JITTER_SAMPLE_COUNT: u32 = 8;
+
+pub struct TaaProperties {
+    pub enabled: bool,
+    /// Weight of the new frame in the history. Lower values are smoother, but
+    /// take longer to catch up with changes.
+    pub current_frame_weight: f32,
+}
+
+impl Default for TaaProperties {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            current_frame_weight: 0.1,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TaaUniform {
+    current_frame_weight: f32,
+    far_depth: f32,
+    reset_history: f32,
+    _padding: f32,
+}
+
+/// Everything that depends on the size of the render.
+struct TaaTargets {
+    velocity: texture::Texture,
+    /// Resolved renders of this and the previous frame, which swap every frame
+    history: [texture::Texture; 2],
+    /// `bind_groups[i]` reads `history[1 - i]`, to render into `history[i]`
+    bind_groups: [wgpu::BindGroup; 2],
+}
+
+/// Temporal anti-aliasing. The projection is jittered by a different subpixel
+/// offset every frame, and every frame is blended into a history of the
+/// previous ones, which converges to a supersampled image at rest.
+///
+/// The lit pass writes motion vectors next to the color, which are used to
+/// find where every pixel was in the history. Since the history can't be
+/// trusted after disocclusions or lighting changes, it is clamped to the colors
+/// around the pixel in the current frame.
+pub struct TaaPipeline {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: Arc<wgpu::Sampler>,
+    uniform_buffer: wgpu::Buffer,
+    targets: TaaTargets,
+    /// History texture that the next frame is resolved into
+    current_history: usize,
+    history_valid: bool,
+    frame_index: u32,
+    width: u32,
+    height: u32,
+    pub properties: TaaProperties,
+}
+
+impl TaaPipeline {
+    /// `color_view` and `depth_view` are the HDR render and depth buffer of the
+    /// lit pass. They need to be passed again to [`Self::resize`] whenever
+    /// they are recreated.
+    pub fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("TAA Bind Group Layout"),
+            entries: &[
+                uniform_entry(0),
+                texture_entry(1, true),
+                texture_entry(2, false),
+                texture_entry(3, true),
+                texture_entry(4, true),
+                sampler_entry(5),
+            ],
+        });
+
+        let pipeline = create_pipeline(
+            device,
+            "TAA Pipeline",
+            &[&bind_group_layout],
+            wgpu_include_slang_shader!("taa"),
+            &[Some(wgpu::ColorTargetState {
+                format: HDR_BUFFER_FORMAT,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        );
+
+        let sampler = sampler_cache.get(
+            device,
+            &wgpu::SamplerDescriptor {
+                label: Some("TAA History Sampler"),
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            },
+        );
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("TAA Uniform Buffer"),
+            size: size_of::<TaaUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let targets = TaaTargets::new(
+            device,
+            sampler_cache,
+            &bind_group_layout,
+            &sampler,
+            &uniform_buffer,
+            color_view,
+            depth_view,
+            width,
+            height,
+        );
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buffer,
+            targets,
+            current_history: 0,
+            history_valid: false,
+            frame_index: 0,
+            width,
+            height,
+            properties: TaaProperties::default(),
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) {
+        self.width = width;
+        self.height = height;
+        self.targets = TaaTargets::new(
+            device,
+            sampler_cache,
+            &self.bind_group_layout,
+            &self.sampler,
+            &self.uniform_buffer,
+            color_view,
+            depth_view,
+            width,
+            height,
+        );
+        self.reset_history();
+    }
+
+    /// Render target for the motion vectors of the lit pass.
+    pub fn velocity_view(&self) -> &wgpu::TextureView {
+        &self.targets.velocity.view
+    }
+
+    /// Starts over from the next frame, for camera cuts and anything else that
+    /// makes the previous frames useless.
+    pub fn reset_history(&mut self) {
+        self.history_valid = false;
+    }
+
+    /// Subpixel offset of the projection in NDC for the next frame, which is
+    /// zero while TAA is off.
+    pub fn next_jitter(&mut self) -> Vector2<f32> {
+        if !self.properties.enabled {
+            return Vector2::zero();
+        }
+
+        self.frame_index = (self.frame_index + 1) % JITTER_SAMPLE_COUNT;
+        // Index 0 of the Halton sequence is 0 in every base, so it is skipped
+        let offset = Vector2::new(
+            halton(self.frame_index + 1, 2),
+            halton(self.frame_index + 1, 3),
+        ) - Vector2::new(0.5, 0.5);
+
+        // A pixel is 2 / size wide in NDC
+        Vector2::new(
+            offset.x * 2.0 / self.width as f32,
+            offset.y * 2.0 / self.height as f32,
+        )
+    }
+
+    pub fn queue_write_binding_resources(&self, queue: &wgpu::Queue, depth_mode: DepthMode) {
+        let uniform = TaaUniform {
+            current_frame_weight: self.properties.current_frame_weight,
+            far_depth: depth_mode.far_depth(),
+            reset_history: if self.history_valid { 0.0 } else { 1.0 },
+            _padding: 0.0,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Resolves `color_texture` into the history and copies the result back.
+    /// It needs to be the texture the color view passed to [`Self::new`] or
+    /// [`Self::resize`] belongs to.
+    pub fn draw(&mut self, encoder: &mut wgpu::CommandEncoder, color_texture: &wgpu::Texture) {
+        let history = &self.targets.history[self.current_history];
+
+        {
+            let mut pass = begin_pass(encoder, "TAA Resolve Pass", &[history]);
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.targets.bind_groups[self.current_history], &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        // The resolve reads the render, so it can't render into it directly
+        encoder.copy_texture_to_texture(
+            history.texture.as_image_copy(),
+            color_texture.as_image_copy(),
+            color_texture.size(),
+        );
+
+        self.current_history = 1 - self.current_history;
+        self.history_valid = true;
+    }
+}
+
+impl TaaTargets {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        uniform_buffer: &wgpu::Buffer,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let width = width.max(1);
+        let height = height.max(1);
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING;
+
+        let velocity = texture::Texture::create_2d_texture(
+            device,
+            sampler_cache,
+            width,
+            height,
+            VELOCITY_FORMAT,
+            usage,
+            wgpu::FilterMode::Nearest,
+            Some("TAA Velocity"),
+        );
+        let history = [0, 1].map(|index| {
+            texture::Texture::create_2d_texture(
+                device,
+                sampler_cache,
+                width,
+                height,
+                HDR_BUFFER_FORMAT,
+                usage | wgpu::TextureUsages::COPY_SRC,
+                wgpu::FilterMode::Linear,
+                Some(&format!("TAA History {index}")),
+            )
+        });
+
+        let bind_groups = [0, 1].map(|index| {
+            let view = |binding, view| wgpu::BindGroupEntry {
+                binding,
+                resource: wgpu::BindingResource::TextureView(view),
+            };
+
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(&format!("TAA Bind Group {index}")),
+                layout: bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buffer.as_entire_binding(),
+                    },
+                    view(1, color_view),
+                    view(2, depth_view),
+                    view(3, &velocity.view),
+                    view(4, &history[1 - index].view),
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                ],
+            })
+        });
+
+        Self {
+            velocity,
+            history,
+            bind_groups,
+        }
+    }
+}
+
+/// Element `index` of the Halton sequence in `base`, which spreads the
+/// samples evenly over [0, 1) however many of them there are.
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut fraction = 1.0;
+    let mut result = 0.0;
+
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+
+    result
+}
@@ -1,53 +1,70 @@
-use anyhow::*;
 use std::sync::Arc;
 use wgpu::{TexelCopyBufferLayout, TextureFormat};
 
 use crate::{
-    ktx2::{get_raw_level_data, ktx_to_wgpu_format, size_and_dims_from_header},
-    resources,
+    asset_server::AssetServer,
+    ktx2::{
+        BasisUniversal, basis_universal_kind, get_raw_level_data, ktx_to_wgpu_format,
+        size_and_dims_from_header, validate_texture_size,
+    },
+    mipmaps::{MipmapGenerator, MipmapMode},
+    resources::ResourceError,
+    sampler_cache::SamplerCache,
 };
 
+/// Everything that can go wrong while turning a KTX2 file into a texture.
+#[derive(Debug, thiserror::Error)]
+pub enum TextureError {
+    #[error("Invalid KTX2 container: {0}")]
+    InvalidContainer(String),
+    #[error("Unsupported texture format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Unsupported supercompression: {0}")]
+    Supercompression(String),
+    #[error("Texture exceeds what the device supports: {0}")]
+    GpuLimits(String),
+    /// Basis Universal textures need to be transcoded to a format the adapter
+    /// supports, and there is no transcoder in the renderer yet.
+    #[error(
+        "Basis Universal ({0:?}) textures can't be transcoded yet, encode it with a BC, ETC2 or ASTC format instead"
+    )]
+    BasisUniversal(BasisUniversal),
+}
+
+/// Layout of a single mip level in memory. Rows of block compressed formats
+/// are rows of blocks, so the size is rounded up to whole blocks first.
 fn buffer_layout_from_wgpu_format(
     format: TextureFormat,
     size: wgpu::Extent3d,
-) -> anyhow::Result<TexelCopyBufferLayout> {
-    match format {
-        TextureFormat::Rgba16Float => Ok(TexelCopyBufferLayout {
-            offset: 0,
-            bytes_per_row: Some(8 * size.width),
-            rows_per_image: Some(size.height),
-        }),
-        TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm | TextureFormat::Rgb9e5Ufloat => {
-            Ok(TexelCopyBufferLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * size.width),
-                rows_per_image: Some(size.height),
-            })
-        }
-        TextureFormat::Rg8Unorm => Ok(TexelCopyBufferLayout {
-            offset: 0,
-            bytes_per_row: Some(2 * size.width),
-            rows_per_image: Some(size.height),
-        }),
-        _ => Err(anyhow!(
-            "TexelCopyBufferLayout unknown for format: {:?}",
-            format
-        )),
-    }
+) -> Result<TexelCopyBufferLayout, TextureError> {
+    let Some(block_size) = format.block_copy_size(None) else {
+        return Err(TextureError::UnsupportedFormat(format!(
+            "TexelCopyBufferLayout unknown for format: {format:?}"
+        )));
+    };
+    let (block_width, block_height) = format.block_dimensions();
+
+    Ok(TexelCopyBufferLayout {
+        offset: 0,
+        bytes_per_row: Some(size.width.div_ceil(block_width) * block_size),
+        rows_per_image: Some(size.height.div_ceil(block_height)),
+    })
 }
 
 pub struct Texture {
     #[allow(unused)]
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
-    pub sampler: wgpu::Sampler,
+    pub sampler: Arc<wgpu::Sampler>,
 }
 
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_2d_texture(
         device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
         width: u32,
         height: u32,
         format: wgpu::TextureFormat,
@@ -62,6 +79,7 @@ impl Texture {
         };
         Self::create_texture(
             device,
+            sampler_cache,
             label,
             size,
             format,
@@ -71,8 +89,10 @@ impl Texture {
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_texture(
         device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
         label: Option<&str>,
         size: wgpu::Extent3d,
         format: wgpu::TextureFormat,
@@ -92,15 +112,18 @@ impl Texture {
         });
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+        let sampler = sampler_cache.get(
+            device,
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter,
+                min_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            },
+        );
 
         Self {
             texture,
@@ -111,6 +134,7 @@ impl Texture {
 
     pub fn create_depth_texture(
         device: &wgpu::Device,
+        sampler_cache: &SamplerCache,
         config: &wgpu::SurfaceConfiguration,
         label: &str,
     ) -> Self {
@@ -138,19 +162,22 @@ impl Texture {
         // we actually don't need a Sampler for the Depth Texture but our implementation
         // of the Texture struct requires it. However, having this sampler allows us
         // to directly render the depth buffer if we ever want to.
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Linear,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            // VVVV this is important when we want to render the depth texture directly
-            compare: Some(wgpu::CompareFunction::LessEqual),
-            lod_min_clamp: 0.0,
-            lod_max_clamp: 100.0,
-            ..Default::default()
-        });
+        let sampler = sampler_cache.get(
+            device,
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                // VVVV this is important when we want to render the depth texture directly
+                compare: Some(wgpu::CompareFunction::LessEqual),
+                lod_min_clamp: 0.0,
+                lod_max_clamp: 100.0,
+                ..Default::default()
+            },
+        );
 
         Self {
             texture,
@@ -162,94 +189,324 @@ impl Texture {
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+        mipmap_generator: Option<&MipmapGenerator>,
         bytes: &[u8],
         options: TextureImportOptions,
-    ) -> Result<Self> {
-        let TextureImportOptions { label, .. } = options;
-
-        let reader = ktx2::Reader::new(bytes)
-            .expect("Can't create Ktx2 reader. Textures need to be Ktx2 files.");
-        Self::texture_from_ktx(device, queue, &reader, label)
+    ) -> Result<Self, TextureError> {
+        let reader = ktx2::Reader::new(bytes).map_err(|err| {
+            TextureError::InvalidContainer(format!("{err}. Textures need to be KTX2 files."))
+        })?;
+        Self::texture_from_ktx(
+            device,
+            queue,
+            sampler_cache,
+            mipmap_generator,
+            &reader,
+            &options,
+        )
     }
 
     pub fn texture_from_ktx(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+        mipmap_generator: Option<&MipmapGenerator>,
         reader: &ktx2::Reader<&[u8]>,
-        label: Option<&str>,
-    ) -> Result<Self> {
+        options: &TextureImportOptions,
+    ) -> Result<Self, TextureError> {
+        let label = options.label;
+        let layout = KtxLayout::from_reader(device, reader, options)?;
+        let format = layout.format;
+
+        // Textures without mips alias badly when minified
+        let generate_mipmaps = match (options.generate_mipmaps, mipmap_generator) {
+            (Some(mode), Some(generator))
+                if layout.level_count == 1 && layout.dimension == wgpu::TextureDimension::D2 =>
+            {
+                if MipmapGenerator::supports_format(device, format) {
+                    Some((generator, mode))
+                } else {
+                    log::warn!(
+                        "Cannot generate mips for texture {label:?} with format {format:?}, it needs to be filterable and renderable."
+                    );
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let texture = if generate_mipmaps.is_some() {
+            layout.create_texture(
+                device,
+                label,
+                layout.size.max_mips(layout.dimension),
+                wgpu::TextureUsages::RENDER_ATTACHMENT,
+            )?
+        } else {
+            layout.create_texture(
+                device,
+                label,
+                layout.level_count,
+                wgpu::TextureUsages::empty(),
+            )?
+        };
+
+        // Handle supercompression
+        let levels = get_raw_level_data(reader)?;
+        if levels.len() != layout.level_count as usize {
+            return Err(TextureError::InvalidContainer(format!(
+                "Expected {} mip levels, but {} were found.",
+                layout.level_count,
+                levels.len()
+            )));
+        }
+
+        for (mip_level, level) in levels.iter().enumerate() {
+            layout.write_level(queue, &texture, mip_level as u32, level)?;
+        }
+
+        // The uploads above are queued, so they happen before the mips get rendered
+        if let Some((generator, mode)) = generate_mipmaps {
+            generator.generate(device, queue, &texture, mode);
+        }
+
+        let view = layout.create_view(&texture, label);
+        let sampler = sampler_cache.get(device, &options.sampler_descriptor());
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+        })
+    }
+}
+
+/// The texture a KTX2 file turns into, after checking that the device can
+/// create it.
+pub(crate) struct KtxLayout {
+    pub format: TextureFormat,
+    pub size: wgpu::Extent3d,
+    pub dimension: wgpu::TextureDimension,
+    pub view_dimension: wgpu::TextureViewDimension,
+    pub level_count: u32,
+}
+
+impl KtxLayout {
+    pub fn from_reader(
+        device: &wgpu::Device,
+        reader: &ktx2::Reader<&[u8]>,
+        options: &TextureImportOptions,
+    ) -> Result<Self, TextureError> {
         let header = reader.header();
 
-        let format = ktx_to_wgpu_format(header.format)?;
+        if let Some(kind) = basis_universal_kind(reader) {
+            return Err(TextureError::BasisUniversal(kind));
+        }
 
-        let (size, dimension, view_dimension) = size_and_dims_from_header(header);
-        let mut size = size;
+        let format = options.apply_srgb_override(ktx_to_wgpu_format(header.format)?);
 
-        let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label,
+        let missing_features = format.required_features() - device.features();
+        if !missing_features.is_empty() {
+            return Err(TextureError::GpuLimits(format!(
+                "Format {format:?} needs the device features {missing_features:?} that the adapter doesn't support."
+            )));
+        }
+
+        let (size, dimension, view_dimension) = size_and_dims_from_header(header)?;
+        // A level count of 0 asks the loader to generate mips, which is only
+        // done when the import options ask for it too
+        let level_count = header.level_count.max(1);
+
+        let (block_width, block_height) = format.block_dimensions();
+        if size.width % block_width != 0 || size.height % block_height != 0 {
+            return Err(TextureError::UnsupportedFormat(format!(
+                "Texture is {}x{}, which is not a multiple of the {block_width}x{block_height} blocks of its format {format:?}.",
+                size.width, size.height
+            )));
+        }
+
+        Ok(Self {
+            format,
             size,
-            mip_level_count: header.level_count,
-            sample_count: 1,
             dimension,
-            format,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_dimension,
+            level_count,
+        })
+    }
+
+    /// Creates the texture with room for `mip_level_count` levels. It can always
+    /// be sampled and copied to, `usage` is added on top of that.
+    pub fn create_texture(
+        &self,
+        device: &wgpu::Device,
+        label: Option<&str>,
+        mip_level_count: u32,
+        usage: wgpu::TextureUsages,
+    ) -> Result<wgpu::Texture, TextureError> {
+        validate_texture_size(&device.limits(), self.size, self.dimension, mip_level_count)?;
+
+        Ok(device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size: self.size,
+            mip_level_count,
+            sample_count: 1,
+            dimension: self.dimension,
+            format: self.format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | usage,
             view_formats: &[],
-        });
+        }))
+    }
 
-        // Handle supercompression
-        let levels = get_raw_level_data(reader)?;
+    /// Copies a decompressed level into the texture. A level holds all layers,
+    /// faces and depth slices, which matches the layout wgpu expects for
+    /// depth_or_array_layers.
+    pub fn write_level(
+        &self,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        mip_level: u32,
+        level: &[u8],
+    ) -> Result<(), TextureError> {
+        // Compressed mips smaller than a block still take up a whole block
+        let mip_size = self
+            .size
+            .mip_level_size(mip_level, self.dimension)
+            .physical_size(self.format);
+        let copy_layout = buffer_layout_from_wgpu_format(self.format, mip_size)?;
+
+        let expected_size = copy_layout.bytes_per_row.unwrap_or(0) as usize
+            * copy_layout.rows_per_image.unwrap_or(0) as usize
+            * mip_size.depth_or_array_layers as usize;
+        if level.len() != expected_size {
+            return Err(TextureError::InvalidContainer(format!(
+                "Mip level {mip_level} has {} bytes, but {expected_size} were expected for {mip_size:?}.",
+                level.len()
+            )));
+        }
 
-        // Copy each level (mip) one-by-one into the Texture buffer
-        let mut curr_mip = 0;
-        levels.iter().for_each(|level| {
-            let copy_layout = buffer_layout_from_wgpu_format(format, size).unwrap();
-            queue.write_texture(
-                wgpu::TexelCopyTextureInfo {
-                    aspect: wgpu::TextureAspect::All,
-                    texture: &texture,
-                    mip_level: curr_mip,
-                    origin: wgpu::Origin3d::ZERO,
-                },
-                level,
-                copy_layout,
-                size,
-            );
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                aspect: wgpu::TextureAspect::All,
+                texture,
+                mip_level,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            level,
+            copy_layout,
+            mip_size,
+        );
 
-            // Make size adjustments per mip level
-            curr_mip += 1;
-            size.width /= 2;
-            size.height /= 2;
-        });
+        Ok(())
+    }
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+    pub fn create_view(&self, texture: &wgpu::Texture, label: Option<&str>) -> wgpu::TextureView {
+        texture.create_view(&wgpu::TextureViewDescriptor {
             label,
-            dimension: Some(view_dimension),
-            format: Some(format),
+            dimension: Some(self.view_dimension),
+            format: Some(self.format),
             ..Default::default()
-        });
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        })
+    }
+}
+
+/// How a texture file gets turned into a texture and the sampler that goes with it.
+pub struct TextureImportOptions<'a> {
+    pub label: Option<&'a str>,
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub address_mode_w: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    /// Maximum anisotropy, from 1 (off) to 16. Anisotropic filtering requires
+    /// all filter modes to be linear, otherwise this is ignored.
+    pub anisotropy_clamp: u16,
+    /// Offset added to the mip level the shader picks. Samplers have no LOD bias
+    /// in wgpu, so this is not applied by the sampler. Users of the texture have
+    /// to pass it on to the shader, see [`crate::material::MaterialProperties`].
+    pub lod_bias: f32,
+    /// Forces the texture to be interpreted as sRGB (`Some(true)`) or linear
+    /// (`Some(false)`) instead of the color space stored in the file. Only
+    /// formats with an sRGB variant can be overridden.
+    pub srgb: Option<bool>,
+    /// Generates the mip chain on the GPU if the file only has a single level.
+    /// Needs a [`MipmapGenerator`] to be passed to the loader, the
+    /// [`AssetServer`] always does.
+    pub generate_mipmaps: Option<MipmapMode>,
+}
+
+impl Default for TextureImportOptions<'_> {
+    fn default() -> Self {
+        Self {
+            label: None,
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
             mag_filter: wgpu::FilterMode::Linear,
             min_filter: wgpu::FilterMode::Linear,
             mipmap_filter: wgpu::FilterMode::Linear,
-            ..Default::default()
-        });
-
-        Ok(Self {
-            texture,
-            view,
-            sampler,
-        })
+            anisotropy_clamp: 1,
+            lod_bias: 0.0,
+            srgb: None,
+            generate_mipmaps: None,
+        }
     }
 }
 
-// We want the default values for all these types, so we don't
-// need to explicitly impl Default
-#[derive(Default)]
-pub struct TextureImportOptions<'a> {
-    pub label: Option<&'a str>,
+impl TextureImportOptions<'_> {
+    /// Sets the address mode of all three texture coordinates.
+    pub fn with_address_mode(mut self, address_mode: wgpu::AddressMode) -> Self {
+        self.address_mode_u = address_mode;
+        self.address_mode_v = address_mode;
+        self.address_mode_w = address_mode;
+        self
+    }
+
+    /// The sampler is unlabeled, since samplers are shared between textures
+    /// through the [`SamplerCache`].
+    pub fn sampler_descriptor(&self) -> wgpu::SamplerDescriptor<'static> {
+        let is_linear = [self.mag_filter, self.min_filter, self.mipmap_filter]
+            .iter()
+            .all(|filter| *filter == wgpu::FilterMode::Linear);
+
+        let mut anisotropy_clamp = self.anisotropy_clamp.clamp(1, 16);
+        if anisotropy_clamp > 1 && !is_linear {
+            log::warn!(
+                "Anisotropic filtering requires linear filtering, ignoring it for texture {:?}.",
+                self.label
+            );
+            anisotropy_clamp = 1;
+        }
+
+        wgpu::SamplerDescriptor {
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: self.address_mode_w,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            anisotropy_clamp,
+            ..Default::default()
+        }
+    }
+
+    fn apply_srgb_override(&self, format: TextureFormat) -> TextureFormat {
+        let overridden = match self.srgb {
+            Some(true) => format.add_srgb_suffix(),
+            Some(false) => format.remove_srgb_suffix(),
+            None => format,
+        };
+
+        if self.srgb.is_some() && format.add_srgb_suffix() == format.remove_srgb_suffix() {
+            log::warn!(
+                "Texture {:?} has format {format:?}, which has no sRGB variant. Ignoring the sRGB override.",
+                self.label
+            );
+        }
+
+        overridden
+    }
 }
 
 pub struct FallbackTextures {
@@ -259,33 +516,31 @@ pub struct FallbackTextures {
 }
 
 impl FallbackTextures {
-    pub async fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
-        let base_color = Arc::new(
-            resources::load_texture(
+    pub async fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        asset_server: &AssetServer,
+    ) -> Result<Self, ResourceError> {
+        let base_color = asset_server
+            .load_texture(
                 "default-base-color-srgb.ktx2",
                 device,
                 queue,
                 Default::default(),
             )
-            .await
-            .expect("Failed to load fallback default texture for Base Color."),
-        );
-        let normal = Arc::new(
-            resources::load_texture("default-normal.ktx2", device, queue, Default::default())
-                .await
-                .expect("Failed to load fallback default texture for Normal."),
-        );
-        let arm = Arc::new(
-            resources::load_texture("default-arm.ktx2", device, queue, Default::default())
-                .await
-                .expect("Failed to load fallback default texture for ARM."),
-        );
+            .await?;
+        let normal = asset_server
+            .load_texture("default-normal.ktx2", device, queue, Default::default())
+            .await?;
+        let arm = asset_server
+            .load_texture("default-arm.ktx2", device, queue, Default::default())
+            .await?;
 
-        Self {
+        Ok(Self {
             base_color,
             normal,
             arm,
-        }
+        })
     }
 
     pub fn base_color(&self) -> Arc<Texture> {
@@ -0,0 +1,168 @@
+use crate::{
+    ktx2::decode_level,
+    sampler_cache::SamplerCache,
+    texture::{KtxLayout, Texture, TextureError, TextureImportOptions},
+};
+
+/// Levels with at most this many texels along their longest edge are uploaded
+/// right away, so there is something to render with on the first frame.
+const INITIAL_LEVEL_SIZE: u32 = 64;
+
+/// Levels of the KTX2 file that are still waiting to be uploaded.
+struct PendingLevels {
+    /// The whole file. KTX2 stores every level on its own, so they can be read
+    /// and decompressed independently.
+    bytes: Vec<u8>,
+    layout: KtxLayout,
+    /// Largest level that has been uploaded so far. All smaller levels are
+    /// uploaded too.
+    resident_level: u32,
+    sampler_descriptor: wgpu::SamplerDescriptor<'static>,
+}
+
+impl PendingLevels {
+    /// Decompresses the next larger level and uploads it.
+    fn upload_next_level(
+        &mut self,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+    ) -> Result<(), TextureError> {
+        let mip_level = self.resident_level - 1;
+        let reader = ktx2::Reader::new(self.bytes.as_slice())
+            .map_err(|err| TextureError::InvalidContainer(err.to_string()))?;
+        let level = reader.levels().nth(mip_level as usize).ok_or_else(|| {
+            TextureError::InvalidContainer(format!("Mip level {mip_level} is missing."))
+        })?;
+
+        let data = decode_level(reader.header().supercompression_scheme, level.data)?;
+        self.layout.write_level(queue, texture, mip_level, &data)?;
+        self.resident_level = mip_level;
+
+        Ok(())
+    }
+
+    /// Sampler that keeps shaders from reading the levels that are not there yet.
+    fn sampler_descriptor(&self) -> wgpu::SamplerDescriptor<'static> {
+        wgpu::SamplerDescriptor {
+            lod_min_clamp: self.resident_level as f32,
+            ..self.sampler_descriptor.clone()
+        }
+    }
+}
+
+/// A KTX2 texture that is uploaded from its smallest level up to its largest
+/// one, spread over several frames. Until it is complete, the sampler clamps
+/// `lod_min_clamp` to the levels that have been uploaded, so the texture is
+/// only blurrier than it should be instead of missing.
+///
+/// [`StreamingTexture::stream`] needs to be called once per frame until it is
+/// done, and the bind groups using the texture need to be recreated whenever it
+/// returns `true`, since the sampler changes.
+pub struct StreamingTexture {
+    texture: Texture,
+    pending: Option<PendingLevels>,
+}
+
+impl StreamingTexture {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+        bytes: Vec<u8>,
+        options: TextureImportOptions,
+    ) -> Result<Self, TextureError> {
+        let reader = ktx2::Reader::new(bytes.as_slice()).map_err(|err| {
+            TextureError::InvalidContainer(format!("{err}. Textures need to be KTX2 files."))
+        })?;
+        let layout = KtxLayout::from_reader(device, &reader, &options)?;
+        let level_count = reader.levels().count() as u32;
+        if level_count != layout.level_count {
+            return Err(TextureError::InvalidContainer(format!(
+                "Expected {} mip levels, but {level_count} were found.",
+                layout.level_count
+            )));
+        }
+
+        let texture = layout.create_texture(
+            device,
+            options.label,
+            level_count,
+            wgpu::TextureUsages::empty(),
+        )?;
+        let view = layout.create_view(&texture, options.label);
+
+        let mut pending = PendingLevels {
+            bytes,
+            layout,
+            resident_level: level_count,
+            sampler_descriptor: options.sampler_descriptor(),
+        };
+        // Always upload the smallest level, and all others that are cheap
+        loop {
+            pending.upload_next_level(queue, &texture)?;
+            let mip_size = pending
+                .layout
+                .size
+                .mip_level_size(pending.resident_level, pending.layout.dimension);
+            if pending.resident_level == 0
+                || mip_size.width.max(mip_size.height) * 2 > INITIAL_LEVEL_SIZE
+            {
+                break;
+            }
+        }
+
+        let sampler = sampler_cache.get(device, &pending.sampler_descriptor());
+        let texture = Texture {
+            texture,
+            view,
+            sampler,
+        };
+
+        Ok(Self {
+            texture,
+            pending: (pending.resident_level > 0).then_some(pending),
+        })
+    }
+
+    /// Wraps a texture that is already complete.
+    pub fn resident(texture: Texture) -> Self {
+        Self {
+            texture,
+            pending: None,
+        }
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    /// Uploads the next larger level, if there is one left. Returns whether the
+    /// sampler of the texture changed.
+    pub fn stream(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sampler_cache: &SamplerCache,
+    ) -> bool {
+        let Some(pending) = &mut self.pending else {
+            return false;
+        };
+
+        if let Err(err) = pending.upload_next_level(queue, &self.texture.texture) {
+            // Keep rendering with the levels that made it
+            log::warn!(
+                "Failed to stream mip level {}: {err}",
+                pending.resident_level - 1
+            );
+            self.pending = None;
+            return false;
+        }
+
+        self.texture.sampler = sampler_cache.get(device, &pending.sampler_descriptor());
+        if pending.resident_level == 0 {
+            self.pending = None;
+        }
+
+        true
+    }
+}
@@ -232,11 +232,149 @@ fn factorial_division(n: usize, d: usize) -> Float {
     return r;
 }
 
+/// Windowing functions that attenuate the higher bands of the SH coefficients.
+/// A truncated SH series rings around bright, small light sources, which can
+/// make the reconstructed irradiance negative on the opposite side of the sphere.
+/// Source: https://www.ppsloan.org/publications/shdering.pdf
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Windowing {
+    #[default]
+    None,
+    /// w(l) = (1 + cos(π l / num_bands)) / 2
+    Hanning,
+    /// w(l) = sinc(π l / num_bands)
+    Lanczos,
+    /// Sloan's min-deringing window w(l) = 1 / (1 + λ (l (l + 1))²), with the
+    /// smallest λ that keeps the reconstruction non-negative everywhere.
+    Sloan,
+}
+
+impl Windowing {
+    fn band_factors(self, num_bands: usize, lambda: Float) -> Vec<Float> {
+        let width = num_bands as Float;
+        (0..num_bands)
+            .map(|l| {
+                let l = l as Float;
+                match self {
+                    Windowing::None => 1.0,
+                    Windowing::Hanning => (1.0 + (M_PI * l / width).cos()) / 2.0,
+                    Windowing::Lanczos if l == 0.0 => 1.0,
+                    Windowing::Lanczos => (M_PI * l / width).sin() / (M_PI * l / width),
+                    Windowing::Sloan => 1.0 / (1.0 + lambda * (l * (l + 1.0)).powi(2)),
+                }
+            })
+            .collect()
+    }
+}
+
+fn apply_window(num_bands: usize, sh: &mut [[f32; 3]], factors: &[Float]) {
+    for l in 0..num_bands {
+        let sl = l as isize;
+        for m in (-sl)..(sl + 1) {
+            for channel in sh[sh_index(m, l)].iter_mut() {
+                *channel *= factors[l];
+            }
+        }
+    }
+}
+
+fn apply_windowing(num_bands: usize, windowing: Windowing, sh: &mut [[f32; 3]]) {
+    let lambda = match windowing {
+        Windowing::Sloan => find_sloan_lambda(num_bands, sh),
+        _ => 0.0,
+    };
+
+    apply_window(num_bands, sh, &windowing.band_factors(num_bands, lambda));
+}
+
+/// Bisects for the smallest λ of the min-deringing window that brings the minimum
+/// of the reconstruction back to 0. Returns 0 if it is not negative to begin with.
+fn find_sloan_lambda(num_bands: usize, sh: &[[f32; 3]]) -> Float {
+    let min_with_lambda = |lambda: Float| {
+        let mut windowed = sh.to_vec();
+        let factors = Windowing::Sloan.band_factors(num_bands, lambda);
+        apply_window(num_bands, &mut windowed, &factors);
+        min_channel(min_reconstructed_value(num_bands, &windowed))
+    };
+
+    if num_bands < 2 || min_with_lambda(0.0) >= 0.0 {
+        return 0.0;
+    }
+
+    // Grow the upper bound until only the DC term is left. If even that is
+    // negative, there is nothing the window can do
+    let mut high: Float = 1.0;
+    while min_with_lambda(high) < 0.0 {
+        high *= 2.0;
+        if high > 1e6 {
+            return high;
+        }
+    }
+
+    let mut low: Float = 0.0;
+    for _ in 0..32 {
+        let mid = (low + high) / 2.0;
+        if min_with_lambda(mid) < 0.0 {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+
+    high
+}
+
+fn min_channel(value: [f32; 3]) -> f32 {
+    value[0].min(value[1]).min(value[2])
+}
+
+/// Minimum of the function reconstructed from the coefficients over the sphere,
+/// for each channel. The sphere is sampled with a Fibonacci lattice.
+pub fn min_reconstructed_value(num_bands: usize, sh: &[[f32; 3]]) -> [f32; 3] {
+    const SAMPLE_COUNT: usize = 4096;
+    let golden_angle = M_PI * (3.0 - (5.0 as Float).sqrt());
+
+    let mut min = [Float::MAX; 3];
+    for i in 0..SAMPLE_COUNT {
+        let z = 1.0 - 2.0 * (i as Float + 0.5) / SAMPLE_COUNT as Float;
+        let radius = (1.0 - z * z).sqrt();
+        let phi = golden_angle * i as Float;
+        let dir = Vec3::new(radius * phi.cos(), radius * phi.sin(), z);
+
+        let sh_basis = compute_sh_basis(num_bands, &dir);
+        let mut value = [0.0; 3];
+        for (coeff, basis) in sh.iter().zip(sh_basis) {
+            for channel in 0..3 {
+                value[channel] += coeff[channel] * basis;
+            }
+        }
+
+        for channel in 0..3 {
+            min[channel] = min[channel].min(value[channel]);
+        }
+    }
+
+    min
+}
+
+/// Prints the minimum of [`min_reconstructed_value`], and warns if the
+/// reconstruction goes negative, which windowing can fix.
+pub fn report_min_reconstructed_value(num_bands: usize, sh: &[[f32; 3]]) {
+    let min = min_reconstructed_value(num_bands, sh);
+    println!("Minimum reconstructed value over the sphere: {:?}", min);
+    if min.iter().any(|value| *value < 0.0) {
+        eprintln!(
+            "WARNING: The reconstruction goes negative, which shows up as dark spots. Try using --windowing sloan."
+        );
+    }
+}
+
 /// Returns spherical harmonics for input cube map.
 /// Input should be 6 square images in the order: +x, -x, +y, -y, +z, -z
 pub fn process(
     num_bands: usize,
     compute_irradiance: bool,
+    windowing: Windowing,
     faces: &Vec<Rgb32FImage>,
 ) -> anyhow::Result<Vec<[f32; 3]>> {
     if faces.len() != 6 {
@@ -303,6 +441,8 @@ pub fn process(
         result.push([n.x, n.y, n.z]);
     }
 
+    apply_windowing(num_bands, windowing, &mut result);
+
     Ok(result)
 }
 
@@ -1,6 +1,6 @@
 use clap::Parser;
 use postcard::to_io;
-use sh_coefficient_baker::{load_cubemap_face, process};
+use sh_coefficient_baker::{Windowing, load_cubemap_face, process, report_min_reconstructed_value};
 use std::{fs::File, path::PathBuf};
 
 /// CLI tool to convert .exr cubemap faces into spherical harmonics coefficients with given number of bands.
@@ -23,6 +23,10 @@ struct Args {
     #[arg(short, long)]
     compute_irradiance: bool,
 
+    /// Windowing function applied to the SH coefficients to reduce ringing around bright light sources.
+    #[arg(short, long, value_enum, default_value_t = Windowing::None)]
+    windowing: Windowing,
+
     output_path: PathBuf,
 }
 
@@ -51,8 +55,15 @@ fn main() {
         if args.compute_irradiance { "" } else { "out" },
     );
 
-    let sh_coefs = process(args.num_bands, args.compute_irradiance, &faces)
-        .expect("Failed to extract SH coefficients from cube map faces.");
+    let sh_coefs = process(
+        args.num_bands,
+        args.compute_irradiance,
+        args.windowing,
+        &faces,
+    )
+    .expect("Failed to extract SH coefficients from cube map faces.");
+
+    report_min_reconstructed_value(args.num_bands, &sh_coefs);
 
     let file = File::create(&args.output_path).expect("Failed to create output file");
 